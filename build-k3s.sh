@@ -1,4 +1,4 @@
 #!/usr/bin/env sh
 set -eux -o pipefail
-nerdctl build . -t expedition-backend:latest --build-arg DATABASE_URL=$DATABASE_URL
+nerdctl build . -t expedition-backend:latest --build-arg DATABASE_URL=$DATABASE_URL --build-arg GIT_SHA=$(git rev-parse --short HEAD)
 nerdctl save expedition-backend:latest | sudo nerdctl --address /var/run/k3s/containerd/containerd.sock --namespace k8s.io load
@@ -0,0 +1 @@
+ALTER TABLE rides DROP COLUMN preview_geo_json;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE rides ADD COLUMN preview_geo_json jsonb;
\ No newline at end of file
@@ -0,0 +1,7 @@
+ALTER TABLE rides
+    DROP COLUMN surface_class,
+    DROP COLUMN difficulty,
+    DROP COLUMN min_lon,
+    DROP COLUMN min_lat,
+    DROP COLUMN max_lon,
+    DROP COLUMN max_lat;
\ No newline at end of file
@@ -0,0 +1,18 @@
+ALTER TABLE rides
+    ADD COLUMN surface_class text,
+    ADD COLUMN difficulty text,
+    ADD COLUMN min_lon double precision,
+    ADD COLUMN min_lat double precision,
+    ADD COLUMN max_lon double precision,
+    ADD COLUMN max_lat double precision;
+
+-- Tracks are imported with their own bounding boxes, combine them for existing rides
+UPDATE rides SET (min_lon, min_lat, max_lon, max_lat) = (
+    SELECT
+        min((f->'bbox'->>0)::double precision),
+        min((f->'bbox'->>1)::double precision),
+        max((f->'bbox'->>2)::double precision),
+        max((f->'bbox'->>3)::double precision)
+    FROM jsonb_array_elements(geo_json->'features') f
+    WHERE f ? 'bbox'
+);
@@ -0,0 +1,5 @@
+DROP TABLE way_coverage;
+DROP INDEX rides_user_id;
+ALTER TABLE rides
+    DROP COLUMN user_id,
+    DROP COLUMN ridden_at;
\ No newline at end of file
@@ -0,0 +1,18 @@
+ALTER TABLE rides
+    ADD COLUMN user_id text,
+    ADD COLUMN ridden_at timestamptz not null default now();
+
+CREATE INDEX rides_user_id ON rides (user_id);
+
+CREATE TABLE way_coverage (
+    user_id text not null,
+    osm_id bigint not null,
+    name text,
+    region text,
+    -- Longest distance ridden on the way in a single ride, in metres
+    distance double precision not null,
+    first_ridden_at timestamptz not null,
+    last_ridden_at timestamptz not null,
+    times_ridden bigint not null,
+    PRIMARY KEY (user_id, osm_id)
+);
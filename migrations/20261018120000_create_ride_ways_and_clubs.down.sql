@@ -0,0 +1,4 @@
+DROP TABLE club_members;
+DROP TABLE clubs;
+DROP TABLE ride_ways;
+ALTER TABLE rides DROP COLUMN start_address;
\ No newline at end of file
@@ -0,0 +1,36 @@
+ALTER TABLE rides ADD COLUMN start_address jsonb;
+
+CREATE TABLE ride_ways (
+    ride_id bigint not null REFERENCES rides (id) ON DELETE CASCADE,
+    seq bigint not null,
+    osm_id bigint not null,
+    name text,
+    surface text,
+    region text,
+    distance double precision not null,
+    PRIMARY KEY (ride_id, seq)
+);
+
+CREATE INDEX ride_ways_osm_id ON ride_ways (osm_id);
+
+INSERT INTO ride_ways (ride_id, seq, osm_id, name, surface, region, distance)
+SELECT
+    rides.id,
+    (way->>'seq')::bigint,
+    (way->>'osm_id')::bigint,
+    way->>'name',
+    way->>'surface',
+    way->>'region',
+    (way->>'distance')::double precision
+FROM rides CROSS JOIN jsonb_array_elements(rides.ways) AS way;
+
+CREATE TABLE clubs (
+    id bigint GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name text not null
+);
+
+CREATE TABLE club_members (
+    club_id bigint not null REFERENCES clubs (id) ON DELETE CASCADE,
+    user_id text not null,
+    PRIMARY KEY (club_id, user_id)
+);
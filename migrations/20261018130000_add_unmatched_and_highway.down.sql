@@ -0,0 +1,2 @@
+ALTER TABLE ride_ways DROP COLUMN highway;
+ALTER TABLE rides DROP COLUMN unmatched;
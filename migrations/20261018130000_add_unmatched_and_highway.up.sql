@@ -0,0 +1,2 @@
+ALTER TABLE rides ADD COLUMN unmatched JSONB;
+ALTER TABLE ride_ways ADD COLUMN highway TEXT;
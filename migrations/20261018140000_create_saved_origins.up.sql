@@ -0,0 +1,14 @@
+CREATE TABLE saved_origins (
+    id bigserial PRIMARY KEY,
+    user_id text not null,
+    name text not null,
+    -- What the user typed, if the origin was looked up from it
+    address text,
+    lat double precision not null,
+    lon double precision not null,
+    is_default boolean not null default false
+);
+
+CREATE INDEX saved_origins_user_id ON saved_origins (user_id);
+-- Each user has at most one default origin
+CREATE UNIQUE INDEX saved_origins_default ON saved_origins (user_id) WHERE is_default;
@@ -0,0 +1 @@
+ALTER TABLE club_members DROP COLUMN accepted;
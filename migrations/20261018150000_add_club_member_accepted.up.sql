@@ -0,0 +1,3 @@
+-- Members are invited and only see each other's stats once they accept.
+-- Nobody agreed to the memberships made so far, so they start out as invites.
+ALTER TABLE club_members ADD COLUMN accepted boolean not null default false;
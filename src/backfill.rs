@@ -0,0 +1,84 @@
+use color_eyre::eyre::Result;
+use geojson::GeoJson;
+use sqlx::{types::Json, PgPool};
+use tracing::{info, instrument, warn};
+
+use crate::{clients::AppState, ride::preview};
+
+/// Rides read at a time
+const BATCH_SIZE: i64 = 100;
+
+/// Fill in what rides imported before a column was added are missing, in the background so startup isn't held up
+pub async fn run(app: AppState) {
+    if let Err(e) = previews(&app.db).await {
+        warn!("Backfilling previews failed: {e:#}");
+    }
+    if let Err(e) = ride_ways(&app.db).await {
+        warn!("Backfilling ride ways failed: {e:#}");
+    }
+}
+
+/// Simplify a preview for every ride without one
+#[instrument(skip(pool))]
+async fn previews(pool: &PgPool) -> Result<()> {
+    let mut last_id = 0;
+    let mut filled = 0;
+    loop {
+        let rides = sqlx::query!(
+            r#"select id, geo_json as "geo_json!: Json<GeoJson>"
+            from rides
+            where preview_geo_json is null and geo_json is not null and id > $1
+            order by id
+            limit $2"#,
+            last_id,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await?;
+        let Some(last) = rides.last() else {
+            break;
+        };
+        last_id = last.id;
+        for ride in rides {
+            sqlx::query!(
+                "update rides set preview_geo_json = $2 where id = $1",
+                ride.id,
+                Json(preview(&ride.geo_json.0)) as _
+            )
+            .execute(pool)
+            .await?;
+            filled += 1;
+        }
+    }
+    if filled > 0 {
+        info!("Filled previews for {filled} rides");
+    }
+    Ok(())
+}
+
+/// Normalise the ways of rides imported before ride_ways, or while it was being created
+#[instrument(skip(pool))]
+async fn ride_ways(pool: &PgPool) -> Result<()> {
+    let filled = sqlx::query!(
+        r#"insert into ride_ways (ride_id, seq, osm_id, name, highway, surface, region, distance)
+        select
+        rides.id,
+        (way->>'seq')::bigint,
+        (way->>'osm_id')::bigint,
+        way->>'name',
+        way->>'highway',
+        way->>'surface',
+        way->>'region',
+        (way->>'distance')::double precision
+        from rides cross join jsonb_array_elements(rides.ways) as way
+        where not exists (select 1 from ride_ways where ride_ways.ride_id = rides.id)
+        on conflict do nothing"#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if filled > 0 {
+        info!("Filled {filled} ride ways");
+    }
+    Ok(())
+}
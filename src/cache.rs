@@ -0,0 +1,53 @@
+use std::{collections::HashMap, hash::Hash, sync::Mutex};
+
+/// A map of results which are slow to look up, emptied whenever it fills so it can't grow without bound
+pub struct Cache<K, V> {
+    capacity: usize,
+    entries: Mutex<HashMap<K, V>>,
+}
+
+impl<K: Hash + Eq, V: Clone> Cache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.entries
+            .lock()
+            .expect("Cache poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    /// Look up several keys under a single lock
+    pub fn get_all<'a>(&self, keys: impl IntoIterator<Item = &'a K>) -> Vec<Option<V>>
+    where
+        K: 'a,
+    {
+        let entries = self.entries.lock().expect("Cache poisoned");
+        keys.into_iter()
+            .map(|key| entries.get(key).cloned())
+            .collect()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_all([(key, value)]);
+    }
+
+    /// Add several entries under a single lock, first emptying the cache if they wouldn't fit
+    pub fn insert_all(&self, new: impl IntoIterator<Item = (K, V)>) {
+        let new: Vec<(K, V)> = new.into_iter().collect();
+        let mut entries = self.entries.lock().expect("Cache poisoned");
+        if entries.len() + new.len() > self.capacity {
+            entries.clear();
+        }
+        entries.extend(new);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().expect("Cache poisoned").clear();
+    }
+}
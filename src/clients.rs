@@ -1,28 +1,92 @@
-use std::sync::OnceLock;
+use std::sync::Arc;
 
-use color_eyre::eyre::{eyre, Result};
-use google_maps::GoogleMapsClient;
-use sqlx::{Pool, Postgres};
+use color_eyre::eyre::Result;
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use tracing::info;
 
-pub static DB_POOL: OnceLock<Pool<Postgres>> = OnceLock::new();
-pub static REQWEST: OnceLock<reqwest::Client> = OnceLock::new();
-pub static NOMINATIM_URL: OnceLock<String> = OnceLock::new();
-pub static GMAPS: OnceLock<GoogleMapsClient> = OnceLock::new();
+use crate::{
+    cache::Cache,
+    config::Config,
+    dem::Dem,
+    drive_times::{self, DriveTimeCache},
+    geocoder::{AnyGeocoder, SearchCache, SEARCH_CACHE_CAPACITY},
+    osm_index::OsmIndex,
+    routing::AnyRoutingProvider,
+    tiles::{self, TileCache},
+    upstream::Upstreams,
+};
 
-pub fn get_db_pool() -> Result<&'static Pool<Postgres>> {
-    DB_POOL.get().ok_or(eyre!("Failed to get db"))
+/// Everything handlers share, built once from the config at startup
+pub struct App {
+    pub config: Config,
+    pub db: Pool<Postgres>,
+    pub geocoder: AnyGeocoder,
+    //Optional, drive times are left out without one
+    pub routing: Option<AnyRoutingProvider>,
+    //Optional, when set ways are looked up in it instead of the geocoder
+    pub osm_index: Option<OsmIndex>,
+    //Optional, fills in elevations for rides recorded without them
+    pub dem: Option<Dem>,
+    //Limits and health of every external service, shared with the geocoder and routing provider
+    pub upstreams: Arc<Upstreams>,
+    pub drive_time_cache: DriveTimeCache,
+    pub search_cache: SearchCache,
+    pub tile_cache: TileCache,
 }
 
-pub fn get_reqwest_client() -> Result<&'static reqwest::Client> {
-    REQWEST.get().ok_or(eyre!("Failed to get reqwest client"))
-}
+/// The App as handlers receive it
+pub type AppState = Arc<App>;
 
-pub fn get_nominatim_url() -> Result<&'static String> {
-    NOMINATIM_URL
-        .get()
-        .ok_or(eyre!("Failed to get nominatim url"))
-}
+impl App {
+    /// Connect to the database and set up the configured providers
+    pub async fn new(config: Config) -> Result<AppState> {
+        info!("Connecting to db");
+        let db = PgPoolOptions::new()
+            .max_connections(config.database.max_connections)
+            .acquire_timeout(config.database.acquire_timeout())
+            .connect(&config.database.url)
+            .await?;
+        info!("Connected");
+        App::with_db(config, db).await
+    }
+
+    /// Set up the configured providers around an existing database pool
+    pub async fn with_db(config: Config, db: Pool<Postgres>) -> Result<AppState> {
+        let upstreams = Arc::new(Upstreams::new(&config.upstream)?);
+        let geocoder = AnyGeocoder::new(
+            &config.geocoder.kind,
+            config.geocoder.url.clone(),
+            upstreams.clone(),
+        )?;
+        let routing = config
+            .routing
+            .provider()
+            .map(|(kind, key_or_url)| {
+                AnyRoutingProvider::new(kind, key_or_url.to_string(), upstreams.clone())
+            })
+            .transpose()?;
+        let osm_index = match config.features.osm_extract.clone() {
+            Some(extract) => {
+                Some(tokio::task::spawn_blocking(move || OsmIndex::load(&extract)).await??)
+            }
+            None => None,
+        };
+        let dem = config.features.dem_dir.clone().map(Dem::new);
+        Ok(Arc::new(App {
+            config,
+            db,
+            geocoder,
+            routing,
+            osm_index,
+            dem,
+            upstreams,
+            drive_time_cache: Cache::new(drive_times::CACHE_CAPACITY),
+            search_cache: Cache::new(SEARCH_CACHE_CAPACITY),
+            tile_cache: Cache::new(tiles::CACHE_CAPACITY),
+        }))
+    }
 
-pub fn get_google_maps() -> Result<&'static GoogleMapsClient> {
-    GMAPS.get().ok_or(eyre!("Failed to get google maps"))
+    pub fn is_admin(&self, user_id: &str) -> bool {
+        self.config.admin_users.iter().any(|admin| admin == user_id)
+    }
 }
@@ -0,0 +1,213 @@
+use color_eyre::eyre::Result;
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::types::dto::club::Club;
+
+/// Members of a club who have accepted, or None if the user isn't one of them.
+/// Invited users aren't included until they accept, so their stats are never shared without their agreement.
+pub async fn club_members(
+    pool: &PgPool,
+    user_id: &str,
+    club_id: i64,
+) -> Result<Option<Vec<String>>> {
+    let members: Vec<String> = sqlx::query_scalar!(
+        r#"select user_id from club_members
+        where club_id = $1 and accepted
+        order by user_id"#,
+        club_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(members
+        .iter()
+        .any(|member| member == user_id)
+        .then_some(members))
+}
+
+/// Every club the user has accepted, or only been invited to, with their members and invites
+async fn clubs(pool: &PgPool, user_id: &str, accepted: bool) -> Result<Vec<Club>> {
+    Ok(sqlx::query_as!(
+        Club,
+        r#"select
+        clubs.id,
+        clubs.name,
+        coalesce(
+            array_agg(members.user_id order by members.user_id) filter (where members.accepted),
+            '{}'
+        ) as "members!",
+        coalesce(
+            array_agg(members.user_id order by members.user_id) filter (where not members.accepted),
+            '{}'
+        ) as "invited!"
+        from clubs
+        join club_members on club_members.club_id = clubs.id
+            and club_members.user_id = $1
+            and club_members.accepted = $2
+        join club_members members on members.club_id = clubs.id
+        group by clubs.id
+        order by clubs.name"#,
+        user_id,
+        accepted
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Every club the user is a member of
+pub async fn user_clubs(pool: &PgPool, user_id: &str) -> Result<Vec<Club>> {
+    clubs(pool, user_id, true).await
+}
+
+/// Every club the user has been invited to and not yet accepted
+pub async fn user_invites(pool: &PgPool, user_id: &str) -> Result<Vec<Club>> {
+    clubs(pool, user_id, false).await
+}
+
+/// Start a club with the user as its first member
+#[instrument(skip(pool))]
+pub async fn create_club(pool: &PgPool, user_id: &str, name: &str) -> Result<Club> {
+    let mut tx = pool.begin().await?;
+    let club_id = sqlx::query_scalar!(r#"insert into clubs (name) values ($1) returning id"#, name)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query!(
+        r#"insert into club_members (club_id, user_id, accepted) values ($1, $2, true)"#,
+        club_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Club {
+        id: club_id,
+        name: name.to_string(),
+        members: vec![user_id.to_string()],
+        invited: Vec::new(),
+    })
+}
+
+/// Invite a user to a club, by any of its members. They only become a member once they accept.
+/// Returns the club after, or None if the user isn't one of its members.
+#[instrument(skip(pool))]
+pub async fn invite_member(
+    pool: &PgPool,
+    user_id: &str,
+    club_id: i64,
+    member: &str,
+) -> Result<Option<Club>> {
+    if club_members(pool, user_id, club_id).await?.is_none() {
+        return Ok(None);
+    }
+    sqlx::query!(
+        r#"insert into club_members (club_id, user_id) values ($1, $2)
+        on conflict do nothing"#,
+        club_id,
+        member
+    )
+    .execute(pool)
+    .await?;
+    Ok(user_clubs(pool, user_id)
+        .await?
+        .into_iter()
+        .find(|club| club.id == club_id))
+}
+
+/// Accept an invite to a club, making the user a member.
+/// Returns whether the user had been invited.
+#[instrument(skip(pool))]
+pub async fn accept_invite(pool: &PgPool, user_id: &str, club_id: i64) -> Result<bool> {
+    Ok(sqlx::query!(
+        r#"update club_members set accepted = true
+        where club_id = $1 and user_id = $2 and not accepted"#,
+        club_id,
+        user_id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected()
+        > 0)
+}
+
+/// Remove a member or invite from a club, by any of its members.
+/// Users can also remove themselves, to leave a club or decline an invite.
+/// The club is deleted with its last member.
+/// Returns whether the user could and the other user was removed.
+#[instrument(skip(pool))]
+pub async fn remove_member(
+    pool: &PgPool,
+    user_id: &str,
+    club_id: i64,
+    member: &str,
+) -> Result<bool> {
+    if user_id != member && club_members(pool, user_id, club_id).await?.is_none() {
+        return Ok(false);
+    }
+    let mut tx = pool.begin().await?;
+    let removed = sqlx::query!(
+        r#"delete from club_members where club_id = $1 and user_id = $2"#,
+        club_id,
+        member
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    sqlx::query!(
+        r#"delete from clubs
+        where id = $1 and not exists (select 1 from club_members where club_id = $1 and accepted)"#,
+        club_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::riding_stats;
+
+    async fn add_ride(pool: &PgPool, user_id: &str, name: &str) {
+        sqlx::query!(
+            r#"insert into rides (name, geo_json, total_distance, ways, user_id)
+            values ($1, '{}', 1000, '[]', $2)"#,
+            name,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn club_ride_names(pool: &PgPool, user_id: &str, club_id: i64) -> (i64, Option<String>) {
+        let members = club_members(pool, user_id, club_id).await.unwrap().unwrap();
+        let stats = riding_stats(pool, &members).await.unwrap();
+        (stats.rides, stats.longest_ride.map(|ride| ride.name))
+    }
+
+    #[sqlx::test]
+    async fn invited_users_stats_are_left_out_until_they_accept(pool: PgPool) {
+        add_ride(&pool, "alice", "Alice's loop").await;
+        add_ride(&pool, "bob", "Bob's secret climb").await;
+        let club = create_club(&pool, "alice", "Snoops").await.unwrap();
+
+        let invited = invite_member(&pool, "alice", club.id, "bob")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invited.members, vec!["alice"]);
+        assert_eq!(invited.invited, vec!["bob"]);
+        assert_eq!(
+            club_ride_names(&pool, "alice", club.id).await,
+            (1, Some(String::from("Alice's loop")))
+        );
+        // Bob can't see the club's stats either until they've accepted
+        assert!(club_members(&pool, "bob", club.id).await.unwrap().is_none());
+        assert_eq!(user_invites(&pool, "bob").await.unwrap()[0].id, club.id);
+
+        assert!(accept_invite(&pool, "bob", club.id).await.unwrap());
+        assert_eq!(club_ride_names(&pool, "alice", club.id).await.0, 2);
+    }
+}
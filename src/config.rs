@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::upstream::{parse_limits, Limits};
+
+/// Read when EXPEDITION_CONFIG doesn't name another file, if it exists
+const DEFAULT_CONFIG_PATH: &str = "expedition.toml";
+
+/// Everything the server is configured with, from a TOML file then overridden by environment variables
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub geocoder: GeocoderConfig,
+    pub routing: RoutingConfig,
+    pub upstream: UpstreamConfig,
+    pub features: FeaturesConfig,
+    pub required: RequiredConfig,
+    //Users allowed to see the admin endpoints
+    pub admin_users: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub acquire_timeout_secs: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::new(),
+            max_connections: 5,
+            acquire_timeout_secs: 30,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeocoderConfig {
+    //One of nominatim, photon or pelias
+    pub kind: String,
+    pub url: String,
+}
+
+impl Default for GeocoderConfig {
+    fn default() -> Self {
+        GeocoderConfig {
+            kind: String::from("nominatim"),
+            url: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    //One of google, osrm, valhalla or straight_line, Google when unset if there's an API key
+    pub kind: Option<String>,
+    //For OSRM and Valhalla
+    pub url: Option<String>,
+    pub google_api_key: Option<String>,
+}
+
+impl RoutingConfig {
+    /// The provider's kind and its key or url, or None if drive times are left out
+    pub fn provider(&self) -> Option<(&str, &str)> {
+        match (self.kind.as_deref(), &self.google_api_key) {
+            (Some("google"), key) => Some(("google", key.as_deref().unwrap_or_default())),
+            (Some(kind), _) => Some((kind, self.url.as_deref().unwrap_or_default())),
+            (None, Some(key)) => Some(("google", key)),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub connect_timeout_secs: u64,
+    //Each attempt at a call, retries get their own
+    pub request_timeout_secs: u64,
+    //Per host overrides of the default limits
+    pub limits: HashMap<String, Limits>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            connect_timeout_secs: 5,
+            request_timeout_secs: 10,
+            limits: HashMap::new(),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    //SRTM .hgt tiles to fill in missing elevations from
+    pub dem_dir: Option<PathBuf>,
+    //An OSM .pbf extract to find rides' ways in instead of the geocoder
+    pub osm_extract: Option<PathBuf>,
+    //Metres between positions looked up when finding a ride's ways
+    pub way_sample_spacing: f64,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        FeaturesConfig {
+            dem_dir: None,
+            osm_extract: None,
+            way_sample_spacing: 50.0,
+        }
+    }
+}
+
+/// Dependencies the server won't start or report ready without.
+/// The database is always required, without the others rides are served with warnings.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequiredConfig {
+    pub geocoder: bool,
+    pub routing: bool,
+}
+
+/// An environment variable, treating empty as unset
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| eyre!("{name} is invalid, {value}: {e}"))
+        })
+        .transpose()
+}
+
+impl Config {
+    /// The file named by EXPEDITION_CONFIG, or expedition.toml if there is one, with environment overrides applied, validated
+    pub fn load() -> Result<Self> {
+        let mut config = match env("EXPEDITION_CONFIG") {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read config file {}", path.display()))?;
+        toml::from_str(&text).wrap_err_with(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Override the file with the environment variables deployments were configured by before there was a file
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(bind) = env_parse("EXPEDITION_BIND")? {
+            self.server.bind = bind;
+        }
+        if let Some(url) = env("DATABASE_URL") {
+            self.database.url = url;
+        }
+        if let Some(max_connections) = env_parse("EXPEDITION_DB_MAX_CONNECTIONS")? {
+            self.database.max_connections = max_connections;
+        }
+        if let Some(kind) = env("EXPEDITION_GEOCODER") {
+            self.geocoder.kind = kind;
+        }
+        if let Some(url) =
+            env("EXPEDITION_GEOCODER_URL").or_else(|| env("EXPEDITION_NOMINATIM_URL"))
+        {
+            self.geocoder.url = url;
+        }
+        if let Some(kind) = env("EXPEDITION_ROUTING") {
+            self.routing.kind = Some(kind);
+        }
+        if let Some(url) = env("EXPEDITION_ROUTING_URL") {
+            self.routing.url = Some(url);
+        }
+        if let Some(key) = env("EXPEDITION_GOOGLE_API_KEY") {
+            self.routing.google_api_key = Some(key);
+        }
+        if let Some(limits) = env("EXPEDITION_UPSTREAM_LIMITS") {
+            self.upstream
+                .limits
+                .extend(parse_limits(&limits).wrap_err("EXPEDITION_UPSTREAM_LIMITS is invalid")?);
+        }
+        if let Some(timeout) = env_parse("EXPEDITION_UPSTREAM_TIMEOUT_SECS")? {
+            self.upstream.request_timeout_secs = timeout;
+        }
+        if let Some(dem_dir) = env("EXPEDITION_DEM_DIR") {
+            self.features.dem_dir = Some(dem_dir.into());
+        }
+        if let Some(extract) = env("EXPEDITION_OSM_EXTRACT") {
+            self.features.osm_extract = Some(extract.into());
+        }
+        if let Some(spacing) = env_parse("EXPEDITION_WAY_SAMPLE_SPACING")? {
+            self.features.way_sample_spacing = spacing;
+        }
+        if let Some(required) = env("EXPEDITION_REQUIRED") {
+            self.required = RequiredConfig::default();
+            for dependency in required.split(',').map(str::trim) {
+                match dependency {
+                    "geocoder" => self.required.geocoder = true,
+                    "routing" => self.required.routing = true,
+                    "" => {}
+                    other => {
+                        return Err(eyre!("EXPEDITION_REQUIRED has unknown dependency {other}"))
+                    }
+                }
+            }
+        }
+        if let Some(admins) = env("EXPEDITION_ADMIN_USERS") {
+            self.admin_users = admins
+                .split(',')
+                .map(|admin| admin.trim().to_string())
+                .filter(|admin| !admin.is_empty())
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Check everything at once, so a broken deployment lists all its problems rather than the first
+    pub fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.database.url.is_empty() {
+            problems.push(String::from("database.url (DATABASE_URL) is required"));
+        }
+        if self.database.max_connections == 0 {
+            problems.push(String::from("database.max_connections must be above zero"));
+        }
+        if self.geocoder.url.is_empty() {
+            problems.push(String::from(
+                "geocoder.url (EXPEDITION_GEOCODER_URL) is required",
+            ));
+        } else if let Err(e) = reqwest::Url::parse(&self.geocoder.url) {
+            problems.push(format!("geocoder.url is invalid: {e}"));
+        }
+        if self.geocoder.kind == "photon" && self.features.osm_extract.is_none() {
+            problems.push(String::from(
+                "features.osm_extract (EXPEDITION_OSM_EXTRACT) is required with the photon geocoder, which can't look up ways",
+            ));
+        }
+        if let Some((kind, key_or_url)) = self.routing.provider() {
+            if key_or_url.is_empty() && kind != "straight_line" {
+                problems.push(format!(
+                    "routing.{} is required for {kind}",
+                    if kind == "google" {
+                        "google_api_key"
+                    } else {
+                        "url"
+                    }
+                ));
+            }
+        }
+        if self.required.routing && self.routing.provider().is_none() {
+            problems.push(String::from(
+                "routing is required but no routing provider is configured",
+            ));
+        }
+        if self.upstream.connect_timeout_secs == 0 || self.upstream.request_timeout_secs == 0 {
+            problems.push(String::from("upstream timeouts must be above zero"));
+        }
+        for (host, limits) in &self.upstream.limits {
+            if limits.concurrency == 0 || !(limits.rate.is_finite() && limits.rate > 0.0) {
+                problems.push(format!("upstream.limits for {host} must be above zero"));
+            }
+        }
+        if self.features.way_sample_spacing.is_nan() || self.features.way_sample_spacing <= 0.0 {
+            problems.push(String::from(
+                "features.way_sample_spacing must be above zero",
+            ));
+        }
+        for (name, path) in [
+            ("features.dem_dir", &self.features.dem_dir),
+            ("features.osm_extract", &self.features.osm_extract),
+        ] {
+            if let Some(path) = path.as_ref().filter(|path| !path.exists()) {
+                problems.push(format!("{name} {} doesn't exist", path.display()));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre!("Invalid config:\n  {}", problems.join("\n  ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_problems(limits: &str) -> String {
+        let config: Config = toml::from_str(&format!(
+            "[database]\nurl = \"postgres://localhost/expedition\"\n\
+            [geocoder]\nurl = \"http://localhost:8080\"\n\
+            [upstream.limits.\"example.com\"]\n{limits}"
+        ))
+        .unwrap();
+        config
+            .validate()
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn rejects_rates_which_arent_positive_numbers() {
+        for rate in ["0.0", "-1.0", "nan", "inf"] {
+            assert!(
+                limit_problems(&format!("concurrency = 1\nrate = {rate}"))
+                    .contains("upstream.limits for example.com"),
+                "rate = {rate} was accepted"
+            );
+        }
+        assert_eq!(limit_problems("concurrency = 1\nrate = 2.5"), "");
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::eyre::Result;
+use geo_types::Coord;
+use geojson::GeoJson;
+use sqlx::types::Json;
+use sqlx::{PgConnection, PgPool};
+use tracing::instrument;
+
+use crate::{
+    ride_geo::TrackLines,
+    types::dto::coverage::{Coverage, CoverageQuery, HeatmapCell, RegionCoverage},
+};
+
+/// Rebuild a user's way coverage from the ways of all of their rides.
+/// Run in the same transaction as any of their rides being added or removed, so the two can't disagree.
+#[instrument(skip(conn))]
+pub async fn refresh_coverage(conn: &mut PgConnection, user_id: &str) -> Result<()> {
+    sqlx::query!(r#"delete from way_coverage where user_id = $1"#, user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        r#"insert into way_coverage
+        (user_id, osm_id, name, region, distance, first_ridden_at, last_ridden_at, times_ridden)
+        select
+        $1,
+        (way->>'osm_id')::bigint,
+        max(way->>'name'),
+        max(way->>'region'),
+        max((way->>'distance')::double precision),
+        min(rides.ridden_at),
+        max(rides.ridden_at),
+        count(distinct rides.id)
+        from rides cross join jsonb_array_elements(rides.ways) as way
+        where rides.user_id = $1
+        group by (way->>'osm_id')::bigint"#,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Every cell of a grid the segment from a to b passes through, in order, stepping from each cell to its neighbour
+fn segment_cells(a: Coord, b: Coord, cell_size: f64) -> Vec<(i64, i64)> {
+    let cell = |value: f64| (value / cell_size).floor() as i64;
+    let (mut x, mut y) = (cell(a.x), cell(a.y));
+    let (end_x, end_y) = (cell(b.x), cell(b.y));
+    let (step_x, step_y) = ((end_x - x).signum(), (end_y - y).signum());
+    // How far along the segment, as a fraction, the next edge of each axis is crossed, and how far apart the edges are
+    let crossing = |from: i64, step: i64, start: f64, delta: f64| {
+        if step == 0 {
+            return (f64::INFINITY, f64::INFINITY);
+        }
+        let edge = (from + i64::from(step > 0)) as f64 * cell_size;
+        ((edge - start) / delta, cell_size / delta.abs())
+    };
+    let (mut next_x, delta_x) = crossing(x, step_x, a.x, b.x - a.x);
+    let (mut next_y, delta_y) = crossing(y, step_y, a.y, b.y - a.y);
+    let mut cells = vec![(x, y)];
+    // Exactly one step per cell edge crossed, so rounding can't carry it past the end
+    for _ in 0..(end_x - x).abs() + (end_y - y).abs() {
+        if y == end_y || (x != end_x && next_x < next_y) {
+            x += step_x;
+            next_x += delta_x;
+        } else {
+            y += step_y;
+            next_y += delta_y;
+        }
+        cells.push((x, y));
+    }
+    cells
+}
+
+/// Count the user's rides passing through each cell of a grid.
+/// Every segment of the full track is followed through the cells it crosses, so there are no gaps between points.
+async fn heatmap(pool: &PgPool, user_id: &str, cell_size: f64) -> Result<Vec<HeatmapCell>> {
+    let rides = sqlx::query!(
+        r#"select geo_json as "geo_json!: Json<GeoJson>"
+        from rides
+        where user_id = $1"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut cells = HashMap::<(i64, i64), i64>::new();
+    for ride in rides {
+        let mut ride_cells = HashSet::<(i64, i64)>::new();
+        for line in ride.geo_json.track_lines() {
+            match line.0.as_slice() {
+                [point] => ride_cells.extend(segment_cells(*point, *point, cell_size)),
+                points => points.windows(2).for_each(|segment| {
+                    ride_cells.extend(segment_cells(segment[0], segment[1], cell_size))
+                }),
+            }
+        }
+        ride_cells
+            .into_iter()
+            .for_each(|cell| *cells.entry(cell).or_insert(0) += 1);
+    }
+    Ok(cells
+        .into_iter()
+        .map(|((x, y), rides)| HeatmapCell {
+            lon: x as f64 * cell_size,
+            lat: y as f64 * cell_size,
+            rides,
+        })
+        .collect())
+}
+
+#[instrument(skip(pool))]
+pub async fn user_coverage(
+    pool: &PgPool,
+    user_id: &str,
+    query: &CoverageQuery,
+) -> Result<Coverage> {
+    let totals = sqlx::query!(
+        r#"select
+        coalesce(sum(distance), 0) as "total_distance!",
+        count(*) as "unique_ways!"
+        from way_coverage
+        where user_id = $1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    let regions = sqlx::query_as!(
+        RegionCoverage,
+        r#"select
+        region,
+        sum(distance) as "distance!",
+        count(*) as "unique_ways!"
+        from way_coverage
+        where user_id = $1
+        group by region
+        order by 2 desc"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Coverage {
+        total_distance: totals.total_distance,
+        unique_ways: totals.unique_ways,
+        regions,
+        heatmap: heatmap(pool, user_id, query.cell_size).await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_crosses_every_cell_between_its_ends() {
+        let cells = segment_cells(Coord { x: 0.05, y: 0.05 }, Coord { x: 0.35, y: 0.18 }, 0.1);
+        assert_eq!(cells, vec![(0, 0), (1, 0), (1, 1), (2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn segment_crosses_cells_westwards_and_south() {
+        let cells = segment_cells(
+            Coord { x: -0.05, y: -0.05 },
+            Coord { x: -0.25, y: -0.25 },
+            0.1,
+        );
+        // Each step crosses one edge, the corner of the diagonal is taken through one neighbour
+        assert_eq!(cells.first(), Some(&(-1, -1)));
+        assert_eq!(cells.last(), Some(&(-3, -3)));
+        assert_eq!(cells.len(), 5);
+    }
+
+    #[test]
+    fn point_is_in_its_own_cell() {
+        let point = Coord {
+            x: 151.21,
+            y: -33.87,
+        };
+        assert_eq!(segment_cells(point, point, 0.05), vec![(3024, -678)]);
+    }
+}
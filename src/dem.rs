@@ -0,0 +1,167 @@
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use geojson::FeatureCollection;
+use tracing::{info, instrument};
+
+use crate::{
+    cache::Cache,
+    ride_geo::{SetTrackElevations, TrackPositions},
+};
+
+/// SRTM marks missing samples with this value
+const HGT_VOID: i16 = -32768;
+/// Tiles kept loaded before they're all dropped, each SRTM1 tile takes about 26MB
+const TILE_CAPACITY: usize = 8;
+
+/// A single SRTM .hgt tile, covering one degree of latitude and longitude.
+/// Samples are stored row by row from the north edge, each row running west to east.
+struct HgtTile {
+    size: usize,
+    samples: Vec<i16>,
+}
+
+impl HgtTile {
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let samples: Vec<i16> = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        // SRTM1 tiles are 3601 samples square, SRTM3 1201
+        let size = (samples.len() as f64).sqrt() as usize;
+        if size < 2 || size * size != samples.len() {
+            return Err(eyre!("hgt tile is not square"));
+        }
+        Ok(HgtTile { size, samples })
+    }
+
+    fn height(&self, row: usize, col: usize) -> Option<f64> {
+        let height = self.samples[row * self.size + col];
+        (height != HGT_VOID).then_some(height as f64)
+    }
+
+    /// Bilinearly interpolate the height at a fraction of the way north and east across the tile
+    fn sample(&self, north: f64, east: f64) -> Option<f64> {
+        let max = (self.size - 1) as f64;
+        let row = (1.0 - north) * max;
+        let col = east * max;
+        let (r0, c0) = (row.floor() as usize, col.floor() as usize);
+        let (r1, c1) = ((r0 + 1).min(self.size - 1), (c0 + 1).min(self.size - 1));
+        let (dr, dc) = (row - r0 as f64, col - c0 as f64);
+        let top = self.height(r0, c0)? * (1.0 - dc) + self.height(r0, c1)? * dc;
+        let bottom = self.height(r1, c0)? * (1.0 - dc) + self.height(r1, c1)? * dc;
+        Some(top * (1.0 - dr) + bottom * dr)
+    }
+}
+
+/// Name of the tile whose south west corner is at lat, lon, eg S34E151.hgt
+fn hgt_file_name(lat: i32, lon: i32) -> String {
+    format!(
+        "{ns}{lat:02}{ew}{lon:03}.hgt",
+        ns = if lat < 0 { 'S' } else { 'N' },
+        lat = lat.abs(),
+        ew = if lon < 0 { 'W' } else { 'E' },
+        lon = lon.abs()
+    )
+}
+
+/// Elevation model read from a directory of local SRTM tiles, loading each tile as it's first needed.
+/// Shared by every import, so the few tiles rides are in are only read once.
+pub struct Dem {
+    dir: PathBuf,
+    //By the latitude and longitude of their south west corner, None for tiles which aren't in the directory, eg over the ocean
+    tiles: Cache<(i32, i32), Option<Arc<HgtTile>>>,
+}
+
+impl Dem {
+    pub fn new(dir: PathBuf) -> Self {
+        Dem {
+            dir,
+            tiles: Cache::new(TILE_CAPACITY),
+        }
+    }
+
+    async fn tile(&self, lat: i32, lon: i32) -> Result<Option<Arc<HgtTile>>> {
+        if let Some(tile) = self.tiles.get(&(lat, lon)) {
+            return Ok(tile);
+        }
+        // Not locked while reading, two imports may both read a new tile
+        let path = self.dir.join(hgt_file_name(lat, lon));
+        let tile = match tokio::fs::read(&path).await {
+            Ok(bytes) => Some(Arc::new(HgtTile::from_bytes(&bytes)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => Err(e)?,
+        };
+        self.tiles.insert((lat, lon), tile.clone());
+        Ok(tile)
+    }
+
+    pub async fn elevation(&self, point: &Point) -> Result<Option<f64>> {
+        let (lat, lon) = (point.y().floor(), point.x().floor());
+        let tile = self.tile(lat as i32, lon as i32).await?;
+        Ok(tile.and_then(|tile| tile.sample(point.y() - lat, point.x() - lon)))
+    }
+}
+
+/// Fill in the elevation of every track position from the DEM, if the source recorded none at all
+#[instrument(skip_all)]
+pub async fn fill_elevation(dem: &Dem, feature_collection: &mut FeatureCollection) -> Result<()> {
+    let positions = feature_collection.track_positions();
+    if positions.iter().any(|(_, elevation)| elevation.is_some()) {
+        return Ok(());
+    }
+    info!("Filling elevation for {} points from DEM", positions.len());
+    let mut elevations = Vec::with_capacity(positions.len());
+    for (point, _) in positions.iter() {
+        elevations.push(dem.elevation(point).await?);
+    }
+    feature_collection.set_track_elevations(&mut elevations.into_iter());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tile from its rows, north first
+    fn tile(rows: &[&[i16]]) -> HgtTile {
+        let bytes: Vec<u8> = rows
+            .iter()
+            .flat_map(|row| row.iter())
+            .flat_map(|height| height.to_be_bytes())
+            .collect();
+        HgtTile::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn names_tiles_by_south_west_corner() {
+        assert_eq!(hgt_file_name(-34, 151), "S34E151.hgt");
+        assert_eq!(hgt_file_name(51, -1), "N51W001.hgt");
+        assert_eq!(hgt_file_name(0, 0), "N00E000.hgt");
+    }
+
+    #[test]
+    fn samples_bilinearly_between_corners() {
+        // North west 100, north east 200, south west 0, south east 400
+        let tile = tile(&[&[100, 200], &[0, 400]]);
+        assert_eq!(tile.sample(1.0, 0.0), Some(100.0));
+        assert_eq!(tile.sample(0.0, 1.0), Some(400.0));
+        assert_eq!(tile.sample(1.0, 0.5), Some(150.0));
+        assert_eq!(tile.sample(0.0, 0.5), Some(200.0));
+        assert_eq!(tile.sample(0.5, 0.5), Some(175.0));
+    }
+
+    #[test]
+    fn voids_have_no_height() {
+        let tile = tile(&[&[100, HGT_VOID], &[0, 400]]);
+        assert_eq!(tile.sample(0.5, 0.5), None);
+        // Samples not touching the void still have one
+        assert_eq!(tile.sample(0.0, 0.5), Some(200.0));
+    }
+
+    #[test]
+    fn rejects_tiles_which_arent_square() {
+        assert!(HgtTile::from_bytes(&[0; 6]).is_err());
+    }
+}
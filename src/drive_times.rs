@@ -0,0 +1,366 @@
+use color_eyre::eyre::{eyre, Result};
+use futures::future::join_all;
+use geo::{Contains, VincentyDistance};
+use geo_types::Point;
+use time::OffsetDateTime;
+use tokio::join;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    cache::Cache,
+    routing::{AnyRoutingProvider, DriveDuration, RoutingProvider},
+    types::{dto::drive_time::DriveTimeQuery, model::ride::QueryRide},
+};
+
+/// Origins are rounded to this many steps per degree when caching, about 100m apart
+const ORIGIN_PRECISION: f64 = 1e3;
+/// Departure times are rounded to this many seconds when caching
+const DEPARTURE_PRECISION: i64 = 15 * 60;
+/// Ride start and end points are rounded to this many steps per degree when caching, about 1m apart
+const POINT_PRECISION: f64 = 1e5;
+/// Fastest plausible average speed to anywhere, in metres per second (130km/h).
+/// Rides further than this could take them in the time are dropped without asking the routing provider.
+const MAX_AVERAGE_SPEED: f64 = 36.1;
+/// Drive times kept in the cache before it's emptied
+pub const CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+enum Direction {
+    FromOrigin,
+    ToOrigin,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy)]
+pub struct CacheKey {
+    origin: (i64, i64),
+    point: (i64, i64),
+    direction: Direction,
+    departure: Option<i64>,
+}
+
+/// Drive times already looked up, None for points there's no route to or from
+pub type DriveTimeCache = Cache<CacheKey, Option<DriveDuration>>;
+
+fn rounded(point: &Point, precision: f64) -> (i64, i64) {
+    (
+        (point.x() * precision).round() as i64,
+        (point.y() * precision).round() as i64,
+    )
+}
+
+fn cache_key(
+    origin: &Point,
+    point: &Point,
+    direction: Direction,
+    departure: Option<OffsetDateTime>,
+) -> CacheKey {
+    CacheKey {
+        origin: rounded(origin, ORIGIN_PRECISION),
+        point: rounded(point, POINT_PRECISION),
+        direction,
+        departure: departure.map(|d| d.unix_timestamp().div_euclid(DEPARTURE_PRECISION)),
+    }
+}
+
+/// Drive times for a ride, None where there's no route or no routing provider configured
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DriveTimes {
+    pub to_start: Option<DriveDuration>,
+    pub from_end: Option<DriveDuration>,
+    //Set when the time is None because the routing provider couldn't be reached, not for want of a route
+    pub to_start_failed: bool,
+    pub from_end_failed: bool,
+}
+
+/// A drive time, or why it couldn't be looked up
+type Lookup = std::result::Result<Option<DriveDuration>, String>;
+
+/// Drive times between the origin and each point, in one direction.
+/// Cached times are reused, the rest are requested in as few matrix calls as the provider allows.
+/// A failed call only loses the times in its own batch, which aren't cached so they're retried next time.
+async fn one_way_times(
+    provider: &AnyRoutingProvider,
+    cache: &DriveTimeCache,
+    origin: Point,
+    points: &[Point],
+    direction: Direction,
+    departure: Option<OffsetDateTime>,
+) -> Vec<Lookup> {
+    let keys: Vec<CacheKey> = points
+        .iter()
+        .map(|point| cache_key(&origin, point, direction, departure))
+        .collect();
+    let times = cache.get_all(&keys);
+    let missing: Vec<usize> = (0..points.len()).filter(|i| times[*i].is_none()).collect();
+    if missing.is_empty() {
+        return times.into_iter().map(|time| Ok(time.flatten())).collect();
+    }
+    let missing_points: Vec<Point> = missing.iter().map(|i| points[*i]).collect();
+    let origins: &[Point] = &[origin];
+    let chunks = join_all(
+        missing_points
+            .chunks(provider.max_batch())
+            .map(|chunk| async move {
+                let durations = match direction {
+                    Direction::FromOrigin => provider
+                        .duration_matrix(origins, chunk, departure)
+                        .await?
+                        .into_iter()
+                        .next()
+                        .unwrap_or_default(),
+                    Direction::ToOrigin => provider
+                        .duration_matrix(chunk, origins, departure)
+                        .await?
+                        .into_iter()
+                        .map(|row| row.first().copied().flatten())
+                        .collect(),
+                };
+                if durations.len() != chunk.len() {
+                    return Err(eyre!(
+                        "Expected {} drive times, got {}",
+                        chunk.len(),
+                        durations.len()
+                    ));
+                }
+                Ok::<_, color_eyre::eyre::Error>(durations)
+            }),
+    )
+    .await;
+    debug!(
+        "Requested {} drive times {:?} in {} calls",
+        missing.len(),
+        direction,
+        chunks.len()
+    );
+    let fetched: Vec<Lookup> = missing_points
+        .chunks(provider.max_batch())
+        .zip(chunks)
+        .flat_map(|(chunk, durations)| match durations {
+            Ok(durations) => durations.into_iter().map(Ok).collect::<Vec<Lookup>>(),
+            Err(e) => {
+                warn!(
+                    "Couldn't get {} drive times {direction:?}: {e:#}",
+                    chunk.len()
+                );
+                vec![Err(format!("{e:#}")); chunk.len()]
+            }
+        })
+        .collect();
+    let mut lookups: Vec<Lookup> = times.into_iter().map(|time| Ok(time.flatten())).collect();
+    let mut found = Vec::new();
+    for (i, lookup) in missing.into_iter().zip(fetched) {
+        if let Ok(time) = lookup {
+            found.push((keys[i], time));
+        }
+        lookups[i] = lookup;
+    }
+    cache.insert_all(found);
+    lookups
+}
+
+/// Drive times from the origin to each ride's start, and from each ride's end back to the origin,
+/// batched across all the rides, leaving and returning at the query's times if given
+#[instrument(skip(provider, cache, rides))]
+pub async fn ride_drive_times(
+    provider: Option<&AnyRoutingProvider>,
+    cache: &DriveTimeCache,
+    origin: Option<Point>,
+    query: &DriveTimeQuery,
+    rides: &[QueryRide],
+) -> Result<Vec<DriveTimes>> {
+    let (Some(origin), Some(provider)) = (origin, provider) else {
+        return Ok(vec![DriveTimes::default(); rides.len()]);
+    };
+    let starts = rides
+        .iter()
+        .map(|ride| Ok(ride.start_point.as_ref().ok_or(eyre!("No start point"))?.0))
+        .collect::<Result<Vec<Point>>>()?;
+    let ends = rides
+        .iter()
+        .map(|ride| Ok(ride.end_point.as_ref().ok_or(eyre!("No end point"))?.0))
+        .collect::<Result<Vec<Point>>>()?;
+    let (to_starts, from_ends) = join!(
+        one_way_times(
+            provider,
+            cache,
+            origin,
+            &starts,
+            Direction::FromOrigin,
+            query.depart_at
+        ),
+        one_way_times(
+            provider,
+            cache,
+            origin,
+            &ends,
+            Direction::ToOrigin,
+            query.return_at
+        )
+    );
+    Ok(to_starts
+        .into_iter()
+        .zip(from_ends)
+        .map(|(to_start, from_end)| DriveTimes {
+            to_start: to_start.clone().ok().flatten(),
+            from_end: from_end.clone().ok().flatten(),
+            to_start_failed: to_start.is_err(),
+            from_end_failed: from_end.is_err(),
+        })
+        .collect())
+}
+
+/// Keep the rides whose start is within the drive time of the origin, quickest first.
+/// Rides whose drive time couldn't be looked up are kept after the rest, to be shown with a warning.
+pub fn within_drive_time<T>(
+    mut rides: Vec<(T, DriveTimes)>,
+    max_drive_time: i64,
+) -> Vec<(T, DriveTimes)> {
+    rides.retain(|(_, times)| {
+        times
+            .to_start
+            .map_or(times.to_start_failed, |t| t.typical <= max_drive_time)
+    });
+    rides.sort_by_key(|(_, times)| (times.to_start.is_none(), times.to_start.map(|t| t.typical)));
+    rides
+}
+
+/// Drop rides whose start can't be within the drive time of the origin,
+/// first by straight line distance, then by the provider's isochrone if it has one
+#[instrument(skip(provider, rides))]
+pub async fn prefilter_drive_time(
+    provider: Option<&AnyRoutingProvider>,
+    origin: Point,
+    max_drive_time: i64,
+    rides: Vec<QueryRide>,
+) -> Result<Vec<QueryRide>> {
+    let max_distance = max_drive_time as f64 * MAX_AVERAGE_SPEED;
+    let start = |ride: &QueryRide| ride.start_point.as_ref().map(|point| point.0);
+    let mut rides: Vec<QueryRide> = rides
+        .into_iter()
+        .filter(|ride| {
+            start(ride).is_some_and(|start| {
+                origin
+                    .vincenty_distance(&start)
+                    .is_ok_and(|distance| distance <= max_distance)
+            })
+        })
+        .collect();
+    if let Some(provider) = provider {
+        // The isochrone only saves work, the drive times themselves still filter the rides without it
+        match provider.isochrone(origin, max_drive_time).await {
+            Ok(Some(isochrone)) => {
+                rides.retain(|ride| start(ride).is_some_and(|start| isochrone.contains(&start)))
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Couldn't get isochrone, skipping it: {e:#}"),
+        }
+    }
+    debug!("{} rides might be within drive time", rides.len());
+    Ok(rides)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::types::{BigDecimal, Json};
+
+    use super::*;
+    use crate::routing::straight_line::StraightLine;
+
+    const ORIGIN: Point = Point(geo_types::Coord { x: 151.0, y: -33.0 });
+    const HOUR: i64 = 3600;
+
+    /// A ride starting and ending the given degrees of latitude north of the origin, about 111km each
+    fn ride(id: i64, degrees_north: f64) -> QueryRide {
+        let start = Point::new(ORIGIN.x(), ORIGIN.y() + degrees_north);
+        QueryRide {
+            id,
+            name: format!("Ride {id}"),
+            total_distance: BigDecimal::from(0),
+            geo_json: None,
+            preview_geo_json: None,
+            ways: None,
+            unmatched: None,
+            start_point: Some(Json(start)),
+            end_point: Some(Json(start)),
+        }
+    }
+
+    fn ids<'a>(rides: impl IntoIterator<Item = &'a QueryRide>) -> Vec<i64> {
+        rides.into_iter().map(|ride| ride.id).collect()
+    }
+
+    #[tokio::test]
+    async fn prefilters_by_distance_then_isochrone() {
+        let provider = AnyRoutingProvider::StraightLine(StraightLine::new());
+        // About 30km, 90km and 200km away. Within an hour at 130km/h the first two could be,
+        // but at the straight line's 60km/h only the first is inside its isochrone.
+        let rides = vec![ride(1, 0.27), ride(2, 0.81), ride(3, 1.8)];
+
+        let without_provider = prefilter_drive_time(None, ORIGIN, HOUR, rides)
+            .await
+            .unwrap();
+        assert_eq!(ids(&without_provider), vec![1, 2]);
+
+        let with_provider = prefilter_drive_time(Some(&provider), ORIGIN, HOUR, without_provider)
+            .await
+            .unwrap();
+        assert_eq!(ids(&with_provider), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn isochrone_contains_points_within_drive_time() {
+        let provider = StraightLine::new();
+        let isochrone = provider.isochrone(ORIGIN, HOUR).await.unwrap().unwrap();
+        // The hour's radius is about 60km, half a degree of latitude is about 55km
+        assert!(isochrone.contains(&Point::new(ORIGIN.x(), ORIGIN.y() + 0.5)));
+        assert!(isochrone.contains(&Point::new(ORIGIN.x() - 0.5, ORIGIN.y())));
+        assert!(!isochrone.contains(&Point::new(ORIGIN.x(), ORIGIN.y() - 0.6)));
+    }
+
+    #[tokio::test]
+    async fn filters_and_sorts_by_drive_time() {
+        let provider = AnyRoutingProvider::StraightLine(StraightLine::new());
+        // About 50km, 30km and 67km away, 50, 30 and 67 minutes at 60km/h
+        let rides = vec![ride(1, 0.45), ride(2, 0.27), ride(3, 0.6)];
+
+        let times = ride_drive_times(
+            Some(&provider),
+            &Cache::new(CACHE_CAPACITY),
+            Some(ORIGIN),
+            &DriveTimeQuery::default(),
+            &rides,
+        )
+        .await
+        .unwrap();
+        assert!(times.iter().all(|times| times.from_end.is_some()));
+        let mut rides_with_times: Vec<(QueryRide, DriveTimes)> =
+            rides.into_iter().zip(times).collect();
+        // One the provider couldn't answer for is kept, last
+        rides_with_times.push((
+            ride(4, 0.1),
+            DriveTimes {
+                to_start_failed: true,
+                ..Default::default()
+            },
+        ));
+        // And one with no route is dropped
+        rides_with_times.push((ride(5, 0.1), DriveTimes::default()));
+
+        let within = within_drive_time(rides_with_times, HOUR);
+        assert_eq!(ids(within.iter().map(|(ride, _)| ride)), vec![2, 1, 4]);
+    }
+
+    #[tokio::test]
+    async fn no_drive_times_without_provider() {
+        let times = ride_drive_times(
+            None,
+            &Cache::new(CACHE_CAPACITY),
+            Some(ORIGIN),
+            &DriveTimeQuery::default(),
+            &[ride(1, 0.1)],
+        )
+        .await
+        .unwrap();
+        assert!(times[0].to_start.is_none() && !times[0].to_start_failed);
+    }
+}
@@ -0,0 +1,143 @@
+pub mod nominatim;
+pub mod pelias;
+pub mod photon;
+
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+
+use crate::{
+    cache::Cache,
+    types::dto::{
+        geocode::{GeocodedPlace, SearchFilters},
+        nominatim::{NominatimDetailsPlace, NominatimPlace},
+    },
+    upstream::Upstreams,
+};
+
+use self::{nominatim::Nominatim, pelias::Pelias, photon::Photon};
+
+/// Looks up places by name, location or OSM id.
+/// Results are in Nominatim's shape, whichever geocoder they come from.
+/// Language is an Accept-Language header's value, names are in the geocoder's default language without one.
+pub trait Geocoder {
+    /// Places matching free text, best match first
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        language: Option<&str>,
+    ) -> Result<Vec<GeocodedPlace>>;
+    /// The place at a point, with its address
+    async fn reverse(&self, point: &Point, language: Option<&str>) -> Result<NominatimPlace>;
+    /// An OSM object's details, eg "W" and a way's id
+    async fn details(&self, osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace>;
+    /// Whether the geocoder is up, tried once without retrying
+    async fn check(&self) -> Result<()>;
+}
+
+/// The geocoder chosen by config
+pub enum AnyGeocoder {
+    Nominatim(Nominatim),
+    Photon(Photon),
+    Pelias(Pelias),
+}
+
+impl AnyGeocoder {
+    /// Configure a geocoder by name, which is one of nominatim, photon or pelias
+    pub fn new(kind: &str, base_url: String, upstreams: Arc<Upstreams>) -> Result<Self> {
+        match kind {
+            "nominatim" => Ok(AnyGeocoder::Nominatim(Nominatim::new(base_url, upstreams))),
+            "photon" => Ok(AnyGeocoder::Photon(Photon::new(base_url, upstreams))),
+            "pelias" => Ok(AnyGeocoder::Pelias(Pelias::new(base_url, upstreams))),
+            _ => Err(eyre!("Unknown geocoder {kind}")),
+        }
+    }
+}
+
+impl Geocoder for AnyGeocoder {
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        language: Option<&str>,
+    ) -> Result<Vec<GeocodedPlace>> {
+        match self {
+            AnyGeocoder::Nominatim(geocoder) => geocoder.search(query, filters, language).await,
+            AnyGeocoder::Photon(geocoder) => geocoder.search(query, filters, language).await,
+            AnyGeocoder::Pelias(geocoder) => geocoder.search(query, filters, language).await,
+        }
+    }
+
+    async fn reverse(&self, point: &Point, language: Option<&str>) -> Result<NominatimPlace> {
+        match self {
+            AnyGeocoder::Nominatim(geocoder) => geocoder.reverse(point, language).await,
+            AnyGeocoder::Photon(geocoder) => geocoder.reverse(point, language).await,
+            AnyGeocoder::Pelias(geocoder) => geocoder.reverse(point, language).await,
+        }
+    }
+
+    async fn details(&self, osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace> {
+        match self {
+            AnyGeocoder::Nominatim(geocoder) => geocoder.details(osm_type, osm_id).await,
+            AnyGeocoder::Photon(geocoder) => geocoder.details(osm_type, osm_id).await,
+            AnyGeocoder::Pelias(geocoder) => geocoder.details(osm_type, osm_id).await,
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        match self {
+            AnyGeocoder::Nominatim(geocoder) => geocoder.check().await,
+            AnyGeocoder::Photon(geocoder) => geocoder.check().await,
+            AnyGeocoder::Pelias(geocoder) => geocoder.check().await,
+        }
+    }
+}
+
+/// Place searches kept in the cache before it's emptied
+pub const SEARCH_CACHE_CAPACITY: usize = 10_000;
+
+/// Places already searched for, keyed by normalised query text, None for queries which found nothing
+pub type SearchCache = Cache<String, Option<Point>>;
+
+/// The best match for free text, eg a town name, or None if nothing matches.
+/// Results are cached, as the same few origins are searched for over and over.
+pub async fn search_point(
+    geocoder: &AnyGeocoder,
+    cache: &SearchCache,
+    query: &str,
+) -> Result<Option<Point>> {
+    let key = query.trim().to_lowercase();
+    if let Some(point) = cache.get(&key) {
+        return Ok(point);
+    }
+    let point = geocoder
+        .search(&key, &SearchFilters::default(), None)
+        .await?
+        .first()
+        .map(|place| Point::new(place.lon, place.lat));
+    cache.insert(key, point);
+    Ok(point)
+}
+
+/// Nominatim's name for an OSM object type, from its single letter or full form
+fn osm_type_name(osm_type: &str) -> String {
+    match osm_type {
+        "N" | "node" => String::from("node"),
+        "W" | "way" => String::from("way"),
+        "R" | "relation" => String::from("relation"),
+        other => other.to_lowercase(),
+    }
+}
+
+/// The most preferred language in an Accept-Language header, without its region, eg de from de-CH,de;q=0.9,en;q=0.8.
+/// For geocoders which take a single language code.
+fn primary_language(accept_language: &str) -> Option<String> {
+    accept_language
+        .split(',')
+        .map(|range| range.split(';').next().unwrap_or_default().trim())
+        .find(|range| !range.is_empty() && *range != "*")
+        .and_then(|range| range.split('-').next())
+        .map(str::to_lowercase)
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use serde::Deserialize;
+
+use crate::{
+    types::dto::{
+        geocode::{GeocodedPlace, SearchFilters},
+        nominatim::{NominatimDetailsPlace, NominatimPlace},
+    },
+    upstream::Upstreams,
+};
+
+use super::Geocoder;
+
+pub struct Nominatim {
+    base_url: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Nominatim {
+    pub fn new(base_url: String, upstreams: Arc<Upstreams>) -> Self {
+        Nominatim {
+            base_url,
+            upstreams,
+        }
+    }
+}
+
+/// A search result, with coordinates as strings
+#[derive(Deserialize)]
+struct NominatimSearchPlace {
+    display_name: String,
+    lat: String,
+    lon: String,
+}
+
+/// Nominatim answers reverse lookups with nothing nearby, eg out at sea, with an error body but a success status
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NominatimReverse {
+    Place(Box<NominatimPlace>),
+    Error { error: String },
+}
+
+/// Nominatim's error for a point with nothing nearby
+const UNABLE_TO_GEOCODE: &str = "Unable to geocode";
+
+impl Geocoder for Nominatim {
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        language: Option<&str>,
+    ) -> Result<Vec<GeocodedPlace>> {
+        let mut params = vec![("q", query.to_string()), ("format", String::from("jsonv2"))];
+        if let Some(language) = language {
+            params.push(("accept-language", language.to_string()));
+        }
+        if let Some([min_lon, min_lat, max_lon, max_lat]) = filters.viewbox {
+            params.push((
+                "viewbox",
+                format!("{min_lon},{min_lat},{max_lon},{max_lat}"),
+            ));
+        }
+        if !filters.country_codes.is_empty() {
+            params.push(("countrycodes", filters.country_codes.join(",")));
+        }
+        let places = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .get(format!("{}/search", self.base_url))
+                    .query(&params),
+            )
+            .await?
+            .json::<Vec<NominatimSearchPlace>>()
+            .await?;
+        places
+            .into_iter()
+            .map(|place| {
+                Ok(GeocodedPlace {
+                    display_name: place.display_name,
+                    lat: place.lat.parse()?,
+                    lon: place.lon.parse()?,
+                })
+            })
+            .collect()
+    }
+
+    async fn reverse(&self, point: &Point, language: Option<&str>) -> Result<NominatimPlace> {
+        let url = format!(
+            "{base_url}/reverse?lat={lat}&lon={lon}&extratags=1&format=jsonv2",
+            base_url = self.base_url,
+            lat = point.y(),
+            lon = point.x()
+        );
+        let mut request = self.upstreams.get(url);
+        // Nominatim understands the header's own format, weights included
+        if let Some(language) = language {
+            request = request.query(&[("accept-language", language)]);
+        }
+        let reverse = self
+            .upstreams
+            .send(request)
+            .await?
+            .json::<NominatimReverse>()
+            .await?;
+        match reverse {
+            NominatimReverse::Place(place) => Ok(*place),
+            NominatimReverse::Error { error } if error == UNABLE_TO_GEOCODE => {
+                Ok(NominatimPlace::nowhere())
+            }
+            NominatimReverse::Error { error } => Err(eyre!(
+                "Nominatim couldn't reverse geocode {point:?}: {error}"
+            )),
+        }
+    }
+
+    async fn details(&self, osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace> {
+        let url = format!(
+            "{base_url}/details?osmtype={osm_type}&osmid={osm_id}&addressdetails=1&format=json",
+            base_url = self.base_url
+        );
+        let place = self
+            .upstreams
+            .send(self.upstreams.get(url))
+            .await?
+            .json::<NominatimDetailsPlace>()
+            .await?;
+        Ok(place)
+    }
+
+    async fn check(&self) -> Result<()> {
+        // Responds with an error status when its database is unavailable
+        self.upstreams
+            .probe(
+                self.upstreams
+                    .get(format!("{}/status", self.base_url))
+                    .query(&[("format", "json")]),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_server::{pair, upstreams, StubServer};
+
+    #[tokio::test]
+    async fn searches_with_filters() {
+        let server = StubServer::start(&[(
+            "/search",
+            r#"[{"display_name": "Bathurst, New South Wales, Australia", "lat": "-33.42", "lon": "149.58"}]"#,
+        )]);
+        let nominatim = Nominatim::new(server.url.clone(), upstreams());
+        let filters = SearchFilters {
+            viewbox: Some([149.0, -34.0, 150.0, -33.0]),
+            country_codes: vec![String::from("au"), String::from("nz")],
+        };
+
+        let places = nominatim
+            .search("bathurst", &filters, Some("de-CH,de;q=0.9"))
+            .await
+            .unwrap();
+
+        assert_eq!(server.requests()[0].path(), "/search");
+        let query = server.only_query();
+        assert!(query.contains(&pair("q", "bathurst")));
+        assert!(query.contains(&pair("format", "jsonv2")));
+        assert!(query.contains(&pair("accept-language", "de-CH,de;q=0.9")));
+        assert!(query.contains(&pair("viewbox", "149,-34,150,-33")));
+        assert!(query.contains(&pair("countrycodes", "au,nz")));
+        assert_eq!(places.len(), 1);
+        assert_eq!(
+            places[0].display_name,
+            "Bathurst, New South Wales, Australia"
+        );
+        assert_eq!((places[0].lat, places[0].lon), (-33.42, 149.58));
+    }
+
+    #[tokio::test]
+    async fn reverse_geocodes_road() {
+        let server = StubServer::start(&[(
+            "/reverse",
+            r#"{
+                "osm_type": "way",
+                "osm_id": 1234,
+                "display_name": "Mount Panorama Circuit, Bathurst",
+                "category": "highway",
+                "type": "tertiary",
+                "name": "Mount Panorama Circuit",
+                "address": {"road": "Mount Panorama Circuit", "city": "Bathurst", "state": "New South Wales", "country_code": "au"},
+                "extratags": {"surface": "asphalt"}
+            }"#,
+        )]);
+        let nominatim = Nominatim::new(server.url.clone(), upstreams());
+
+        let place = nominatim
+            .reverse(&Point::new(149.55, -33.44), None)
+            .await
+            .unwrap();
+
+        assert_eq!(server.requests()[0].path(), "/reverse");
+        let query = server.only_query();
+        assert!(query.contains(&pair("lat", "-33.44")));
+        assert!(query.contains(&pair("lon", "149.55")));
+        assert!(query.contains(&pair("extratags", "1")));
+        assert_eq!((place.osm_type.as_str(), place.osm_id), ("way", 1234));
+        assert_eq!(place.category.as_deref(), Some("highway"));
+        assert_eq!(place.place_type.as_deref(), Some("tertiary"));
+        assert_eq!(place.extratags.surface.as_deref(), Some("asphalt"));
+        let address = place.address.unwrap();
+        assert_eq!(address.road.as_deref(), Some("Mount Panorama Circuit"));
+        assert_eq!(address.state.as_deref(), Some("New South Wales"));
+    }
+
+    #[tokio::test]
+    async fn reverse_geocodes_nowhere_as_place_without_address() {
+        let server = StubServer::start(&[("/reverse", r#"{"error": "Unable to geocode"}"#)]);
+        let nominatim = Nominatim::new(server.url.clone(), upstreams());
+
+        let place = nominatim
+            .reverse(&Point::new(160.0, -40.0), None)
+            .await
+            .unwrap();
+
+        assert!(place.address.is_none());
+        assert!(place.category.is_none());
+    }
+
+    #[tokio::test]
+    async fn reverse_fails_on_other_errors() {
+        let server = StubServer::start(&[("/reverse", r#"{"error": "Invalid coordinates"}"#)]);
+        let nominatim = Nominatim::new(server.url.clone(), upstreams());
+
+        assert!(nominatim
+            .reverse(&Point::new(160.0, -40.0), None)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use serde::Deserialize;
+
+use crate::{
+    types::dto::{
+        geocode::{GeocodedPlace, SearchFilters},
+        nominatim::{Address, ExtraTags, NominatimDetailsPlace, NominatimPlace},
+    },
+    upstream::Upstreams,
+};
+
+use super::{osm_type_name, primary_language, Geocoder};
+
+/// Pelias, using places it imported from OpenStreetMap
+pub struct Pelias {
+    base_url: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Pelias {
+    pub fn new(base_url: String, upstreams: Arc<Upstreams>) -> Self {
+        Pelias {
+            base_url,
+            upstreams,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PeliasResponse {
+    features: Vec<PeliasFeature>,
+}
+
+#[derive(Deserialize)]
+struct PeliasFeature {
+    geometry: PeliasGeometry,
+    properties: PeliasProperties,
+}
+
+#[derive(Deserialize)]
+struct PeliasGeometry {
+    //Lon, lat
+    coordinates: [f64; 2],
+}
+
+#[derive(Deserialize)]
+struct PeliasProperties {
+    //Id within the source, for OSM eg way/1234
+    source_id: String,
+    //Kind of place, eg street, address or venue
+    layer: String,
+    label: Option<String>,
+    name: Option<String>,
+    street: Option<String>,
+    postalcode: Option<String>,
+    neighbourhood: Option<String>,
+    locality: Option<String>,
+    county: Option<String>,
+    region: Option<String>,
+    region_a: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+}
+
+impl PeliasProperties {
+    /// The OSM type and id from the source id
+    fn osm_id(&self) -> Result<(String, u64)> {
+        let (osm_type, osm_id) = self
+            .source_id
+            .split_once('/')
+            .ok_or(eyre!("{} is not an OSM id", self.source_id))?;
+        Ok((osm_type_name(osm_type), osm_id.parse()?))
+    }
+}
+
+impl Pelias {
+    async fn features(&self, path: &str, query: &[(&str, String)]) -> Result<Vec<PeliasFeature>> {
+        let response = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .get(format!("{}/v1/{path}", self.base_url))
+                    .query(query),
+            )
+            .await?
+            .json::<PeliasResponse>()
+            .await?;
+        Ok(response.features)
+    }
+}
+
+impl Geocoder for Pelias {
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        language: Option<&str>,
+    ) -> Result<Vec<GeocodedPlace>> {
+        let mut params = vec![("text", query.to_string())];
+        if let Some(language) = language.and_then(primary_language) {
+            params.push(("lang", language));
+        }
+        // Pelias biases towards a point rather than a box
+        if let Some((lon, lat)) = filters.focus() {
+            params.push(("focus.point.lon", lon.to_string()));
+            params.push(("focus.point.lat", lat.to_string()));
+        }
+        if !filters.country_codes.is_empty() {
+            params.push(("boundary.country", filters.country_codes.join(",")));
+        }
+        let features = self.features("search", &params).await?;
+        Ok(features
+            .into_iter()
+            .map(|feature| GeocodedPlace {
+                display_name: feature.properties.label.unwrap_or_default(),
+                lon: feature.geometry.coordinates[0],
+                lat: feature.geometry.coordinates[1],
+            })
+            .collect())
+    }
+
+    async fn reverse(&self, point: &Point, language: Option<&str>) -> Result<NominatimPlace> {
+        let mut params = vec![
+            ("point.lat", point.y().to_string()),
+            ("point.lon", point.x().to_string()),
+            ("sources", String::from("osm")),
+            ("size", String::from("1")),
+        ];
+        if let Some(language) = language.and_then(primary_language) {
+            params.push(("lang", language));
+        }
+        let Some(feature) = self.features("reverse", &params).await?.into_iter().next() else {
+            return Ok(NominatimPlace::nowhere());
+        };
+        let properties = feature.properties;
+        let (osm_type, osm_id) = properties.osm_id()?;
+        let is_road = properties.layer == "street";
+        Ok(NominatimPlace {
+            osm_type,
+            osm_id,
+            display_name: properties.label.clone().unwrap_or_default(),
+            category: is_road.then(|| String::from("highway")),
+            place_type: None,
+            address: Some(Address {
+                road: properties
+                    .street
+                    .or(is_road.then(|| properties.name.clone()).flatten()),
+                suburb: properties.neighbourhood,
+                city: properties.locality,
+                county: properties.county,
+                state: properties.region,
+                iso3166_2_lvl4: properties.region_a,
+                postcode: properties.postalcode,
+                country: properties.country,
+                country_code: properties.country_code.map(|code| code.to_lowercase()),
+                ..Default::default()
+            }),
+            name: properties.name,
+            // Pelias doesn't keep OSM tags
+            extratags: ExtraTags { surface: None },
+        })
+    }
+
+    async fn details(&self, osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace> {
+        let osm_type = osm_type_name(osm_type);
+        let layer = if osm_type == "way" { "street" } else { "venue" };
+        let feature = self
+            .features(
+                "place",
+                &[("ids", format!("openstreetmap:{layer}:{osm_type}/{osm_id}"))],
+            )
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(eyre!("No {osm_type} {osm_id}"))?;
+        Ok(NominatimDetailsPlace {
+            osm_type,
+            osm_id,
+            localname: feature.properties.name.unwrap_or_default(),
+            extratags: ExtraTags { surface: None },
+        })
+    }
+
+    async fn check(&self) -> Result<()> {
+        // Pelias has no status endpoint, the smallest reverse lookup checks its index is reachable
+        self.upstreams
+            .probe(
+                self.upstreams
+                    .get(format!("{}/v1/reverse", self.base_url))
+                    .query(&[("point.lat", "0"), ("point.lon", "0"), ("size", "1")]),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_server::{pair, upstreams, StubServer};
+
+    #[tokio::test]
+    async fn searches_with_filters() {
+        let server = StubServer::start(&[(
+            "/v1/search",
+            r#"{"features": [{"geometry": {"coordinates": [149.58, -33.42]}, "properties": {
+                "source_id": "node/1", "layer": "locality", "label": "Bathurst, NSW, Australia", "name": "Bathurst"
+            }}]}"#,
+        )]);
+        let pelias = Pelias::new(server.url.clone(), upstreams());
+        let filters = SearchFilters {
+            viewbox: Some([149.0, -34.0, 150.0, -33.0]),
+            country_codes: vec![String::from("au")],
+        };
+
+        let places = pelias
+            .search("bathurst", &filters, Some("de-CH,de;q=0.9"))
+            .await
+            .unwrap();
+
+        let query = server.only_query();
+        assert!(query.contains(&pair("text", "bathurst")));
+        assert!(query.contains(&pair("lang", "de")));
+        assert!(query.contains(&pair("focus.point.lon", "149.5")));
+        assert!(query.contains(&pair("focus.point.lat", "-33.5")));
+        assert!(query.contains(&pair("boundary.country", "au")));
+        assert_eq!(places.len(), 1);
+        assert_eq!(places[0].display_name, "Bathurst, NSW, Australia");
+        assert_eq!((places[0].lat, places[0].lon), (-33.42, 149.58));
+    }
+
+    #[tokio::test]
+    async fn reverse_geocodes_street_into_nominatim_shape() {
+        let server = StubServer::start(&[(
+            "/v1/reverse",
+            r#"{"features": [{"geometry": {"coordinates": [149.55, -33.44]}, "properties": {
+                "source_id": "way/1234", "layer": "street", "label": "Mount Panorama Circuit, Bathurst",
+                "name": "Mount Panorama Circuit", "locality": "Bathurst", "region": "New South Wales",
+                "region_a": "NSW", "country": "Australia", "country_code": "AU"
+            }}]}"#,
+        )]);
+        let pelias = Pelias::new(server.url.clone(), upstreams());
+
+        let place = pelias
+            .reverse(&Point::new(149.55, -33.44), None)
+            .await
+            .unwrap();
+
+        let query = server.only_query();
+        assert!(query.contains(&pair("point.lat", "-33.44")));
+        assert!(query.contains(&pair("point.lon", "149.55")));
+        assert!(query.contains(&pair("sources", "osm")));
+        assert!(query.contains(&pair("size", "1")));
+        assert_eq!((place.osm_type.as_str(), place.osm_id), ("way", 1234));
+        assert_eq!(place.category.as_deref(), Some("highway"));
+        assert_eq!(place.display_name, "Mount Panorama Circuit, Bathurst");
+        let address = place.address.unwrap();
+        assert_eq!(address.road.as_deref(), Some("Mount Panorama Circuit"));
+        assert_eq!(address.city.as_deref(), Some("Bathurst"));
+        assert_eq!(address.iso3166_2_lvl4.as_deref(), Some("NSW"));
+        assert_eq!(address.country_code.as_deref(), Some("au"));
+    }
+
+    #[tokio::test]
+    async fn reverse_finds_nowhere_without_a_place() {
+        let server = StubServer::start(&[("/v1/reverse", r#"{"features": []}"#)]);
+        let pelias = Pelias::new(server.url.clone(), upstreams());
+        let place = pelias.reverse(&Point::new(0.0, 0.0), None).await.unwrap();
+        assert!(place.address.is_none());
+        assert_eq!(place.osm_id, 0);
+    }
+
+    #[tokio::test]
+    async fn looks_up_way_details_by_id() {
+        let server = StubServer::start(&[(
+            "/v1/place",
+            r#"{"features": [{"geometry": {"coordinates": [149.55, -33.44]}, "properties": {
+                "source_id": "way/1234", "layer": "street", "name": "Mount Panorama Circuit"
+            }}]}"#,
+        )]);
+        let pelias = Pelias::new(server.url.clone(), upstreams());
+
+        let place = pelias.details("W", 1234).await.unwrap();
+
+        let query = server.only_query();
+        assert_eq!(query, vec![pair("ids", "openstreetmap:street:way/1234")]);
+        assert_eq!((place.osm_type.as_str(), place.osm_id), ("way", 1234));
+        assert_eq!(place.localname, "Mount Panorama Circuit");
+    }
+}
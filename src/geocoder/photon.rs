@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use serde::Deserialize;
+
+use crate::{
+    types::dto::{
+        geocode::{GeocodedPlace, SearchFilters},
+        nominatim::{Address, ExtraTags, NominatimDetailsPlace, NominatimPlace},
+    },
+    upstream::Upstreams,
+};
+
+use super::{osm_type_name, primary_language, Geocoder};
+
+/// Photon, a geocoder built from the same OSM data as Nominatim but without its database.
+/// It can't look up places by id, so way details need a local OSM extract.
+pub struct Photon {
+    base_url: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Photon {
+    pub fn new(base_url: String, upstreams: Arc<Upstreams>) -> Self {
+        Photon {
+            base_url,
+            upstreams,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PhotonResponse {
+    features: Vec<PhotonFeature>,
+}
+
+#[derive(Deserialize)]
+struct PhotonFeature {
+    geometry: PhotonGeometry,
+    properties: PhotonProperties,
+}
+
+#[derive(Deserialize)]
+struct PhotonGeometry {
+    //Lon, lat
+    coordinates: [f64; 2],
+}
+
+#[derive(Deserialize)]
+struct PhotonProperties {
+    //N, W or R
+    osm_type: String,
+    osm_id: u64,
+    //The OSM tag the place was found by, eg highway=track
+    osm_key: Option<String>,
+    osm_value: Option<String>,
+    name: Option<String>,
+    street: Option<String>,
+    postcode: Option<String>,
+    district: Option<String>,
+    city: Option<String>,
+    county: Option<String>,
+    state: Option<String>,
+    country: Option<String>,
+    countrycode: Option<String>,
+}
+
+impl PhotonProperties {
+    fn display_name(&self) -> String {
+        [
+            &self.name,
+            &self.street,
+            &self.city,
+            &self.state,
+            &self.country,
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(", ")
+    }
+}
+
+impl Photon {
+    async fn features(&self, path: &str, query: &[(&str, String)]) -> Result<Vec<PhotonFeature>> {
+        let response = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .get(format!("{}/{path}", self.base_url))
+                    .query(query),
+            )
+            .await?
+            .json::<PhotonResponse>()
+            .await?;
+        Ok(response.features)
+    }
+}
+
+impl Geocoder for Photon {
+    async fn search(
+        &self,
+        query: &str,
+        filters: &SearchFilters,
+        language: Option<&str>,
+    ) -> Result<Vec<GeocodedPlace>> {
+        let mut params = vec![("q", query.to_string())];
+        if let Some(language) = language.and_then(primary_language) {
+            params.push(("lang", language));
+        }
+        // Photon biases towards a point rather than a box
+        if let Some((lon, lat)) = filters.focus() {
+            params.push(("lon", lon.to_string()));
+            params.push(("lat", lat.to_string()));
+        }
+        let features = self.features("api", &params).await?;
+        // Photon can't filter by country, so it's done here
+        Ok(features
+            .into_iter()
+            .filter(|feature| {
+                filters.country_codes.is_empty()
+                    || feature
+                        .properties
+                        .countrycode
+                        .as_ref()
+                        .is_some_and(|code| filters.country_codes.contains(&code.to_lowercase()))
+            })
+            .map(|feature| GeocodedPlace {
+                display_name: feature.properties.display_name(),
+                lon: feature.geometry.coordinates[0],
+                lat: feature.geometry.coordinates[1],
+            })
+            .collect())
+    }
+
+    async fn reverse(&self, point: &Point, language: Option<&str>) -> Result<NominatimPlace> {
+        let mut params = vec![
+            ("lat", point.y().to_string()),
+            ("lon", point.x().to_string()),
+        ];
+        if let Some(language) = language.and_then(primary_language) {
+            params.push(("lang", language));
+        }
+        let Some(feature) = self.features("reverse", &params).await?.into_iter().next() else {
+            return Ok(NominatimPlace::nowhere());
+        };
+        let properties = feature.properties;
+        let display_name = properties.display_name();
+        let is_road = properties.osm_key.as_deref() == Some("highway");
+        Ok(NominatimPlace {
+            osm_type: osm_type_name(&properties.osm_type),
+            osm_id: properties.osm_id,
+            display_name,
+            address: Some(Address {
+                road: properties
+                    .street
+                    .or(is_road.then(|| properties.name.clone()).flatten()),
+                suburb: properties.district,
+                city: properties.city,
+                county: properties.county,
+                state: properties.state,
+                postcode: properties.postcode,
+                country: properties.country,
+                country_code: properties.countrycode.map(|code| code.to_lowercase()),
+                ..Default::default()
+            }),
+            category: properties.osm_key,
+            place_type: properties.osm_value,
+            name: properties.name,
+            // Photon doesn't keep tags beyond the one it indexed the place by
+            extratags: ExtraTags { surface: None },
+        })
+    }
+
+    async fn details(&self, osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace> {
+        Err(eyre!(
+            "Photon can't look up {osm_type} {osm_id} by id, configure an OSM extract for way details"
+        ))
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.upstreams
+            .probe(self.upstreams.get(format!("{}/status", self.base_url)))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub_server::{pair, upstreams, StubServer};
+
+    #[tokio::test]
+    async fn searches_and_filters_by_country() {
+        let server = StubServer::start(&[(
+            "/api",
+            r#"{"features": [
+                {"geometry": {"coordinates": [149.58, -33.42]}, "properties": {"osm_type": "N", "osm_id": 1, "name": "Bathurst", "state": "New South Wales", "country": "Australia", "countrycode": "AU"}},
+                {"geometry": {"coordinates": [-66.0, 47.6]}, "properties": {"osm_type": "N", "osm_id": 2, "name": "Bathurst", "country": "Canada", "countrycode": "CA"}}
+            ]}"#,
+        )]);
+        let photon = Photon::new(server.url.clone(), upstreams());
+        let filters = SearchFilters {
+            viewbox: Some([149.0, -34.0, 150.0, -33.0]),
+            country_codes: vec![String::from("au")],
+        };
+
+        let places = photon
+            .search("bathurst", &filters, Some("de-CH,de;q=0.9"))
+            .await
+            .unwrap();
+
+        let query = server.only_query();
+        assert!(query.contains(&pair("q", "bathurst")));
+        assert!(query.contains(&pair("lang", "de")));
+        assert!(query.contains(&pair("lon", "149.5")));
+        assert!(query.contains(&pair("lat", "-33.5")));
+        assert_eq!(places.len(), 1);
+        assert_eq!(
+            places[0].display_name,
+            "Bathurst, New South Wales, Australia"
+        );
+        assert_eq!((places[0].lat, places[0].lon), (-33.42, 149.58));
+    }
+
+    #[tokio::test]
+    async fn reverse_geocodes_road_into_nominatim_shape() {
+        let server = StubServer::start(&[(
+            "/reverse",
+            r#"{"features": [{"geometry": {"coordinates": [149.55, -33.44]}, "properties": {
+                "osm_type": "W", "osm_id": 1234, "osm_key": "highway", "osm_value": "tertiary",
+                "name": "Mount Panorama Circuit", "city": "Bathurst", "state": "New South Wales",
+                "country": "Australia", "countrycode": "AU"
+            }}]}"#,
+        )]);
+        let photon = Photon::new(server.url.clone(), upstreams());
+
+        let place = photon
+            .reverse(&Point::new(149.55, -33.44), None)
+            .await
+            .unwrap();
+
+        let query = server.only_query();
+        assert!(query.contains(&pair("lat", "-33.44")));
+        assert!(query.contains(&pair("lon", "149.55")));
+        assert_eq!((place.osm_type.as_str(), place.osm_id), ("way", 1234));
+        assert_eq!(place.category.as_deref(), Some("highway"));
+        assert_eq!(place.place_type.as_deref(), Some("tertiary"));
+        let address = place.address.unwrap();
+        // Roads are named by the place itself rather than a street
+        assert_eq!(address.road.as_deref(), Some("Mount Panorama Circuit"));
+        assert_eq!(address.city.as_deref(), Some("Bathurst"));
+        assert_eq!(address.country_code.as_deref(), Some("au"));
+    }
+
+    #[tokio::test]
+    async fn reverse_finds_nowhere_without_a_place() {
+        let server = StubServer::start(&[("/reverse", r#"{"features": []}"#)]);
+        let photon = Photon::new(server.url.clone(), upstreams());
+        let place = photon.reverse(&Point::new(0.0, 0.0), None).await.unwrap();
+        assert!(place.address.is_none());
+        assert_eq!(place.osm_id, 0);
+    }
+
+    #[tokio::test]
+    async fn checks_outside_limits_and_circuit() {
+        let server = StubServer::start(&[("/status", r#"{"status": "Ok"}"#)]);
+        let upstreams = upstreams();
+        let photon = Photon::new(server.url.clone(), upstreams.clone());
+        assert!(photon.check().await.is_ok());
+        let down = Photon::new(format!("{}/down", server.url), upstreams.clone());
+        assert!(down.check().await.is_err());
+        // Neither check was counted against the host
+        assert!(upstreams.statuses().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use tokio::{join, time::timeout};
+use tracing::warn;
+
+use crate::{
+    clients::App,
+    geocoder::Geocoder,
+    routing::google::GOOGLE_MAPS_HOST,
+    types::dto::{status::DependencyStatus, upstream::CircuitState},
+};
+
+/// Longest checking one dependency can take, within a probe's usual timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The host of a url, to find how calls to it are going
+fn url_host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(str::to_string)
+}
+
+/// Run a check within the timeout, with how long it took to get an answer
+async fn timed(check: impl Future<Output = Result<()>>) -> (Result<()>, Option<Duration>) {
+    let started = Instant::now();
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => (result, Some(started.elapsed())),
+        Err(_) => (Err(eyre!("Timed out after {CHECK_TIMEOUT:?}")), None),
+    }
+}
+
+async fn check_database(app: &App) -> DependencyStatus {
+    let (result, latency) = timed(async {
+        sqlx::query("select 1").execute(&app.db).await?;
+        Ok::<_, color_eyre::eyre::Error>(())
+    })
+    .await;
+    DependencyStatus {
+        name: "database",
+        required: true,
+        healthy: result.is_ok(),
+        latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        error: result.err().map(|e| format!("{e:#}")),
+        upstream: None,
+    }
+}
+
+async fn check_geocoder(app: &App) -> DependencyStatus {
+    let (result, latency) = timed(app.geocoder.check()).await;
+    DependencyStatus {
+        name: "geocoder",
+        required: app.config.required.geocoder,
+        healthy: result.is_ok(),
+        latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        error: result.err().map(|e| format!("{e:#}")),
+        upstream: url_host(&app.config.geocoder.url).and_then(|host| app.upstreams.status(&host)),
+    }
+}
+
+/// Routing providers have no cheap call to check them with, so they're judged by their config and their circuit
+fn check_routing(app: &App) -> DependencyStatus {
+    let host = match app.config.routing.provider() {
+        Some(("google", _)) => Some(GOOGLE_MAPS_HOST.to_string()),
+        Some((_, url)) => url_host(url),
+        None => None,
+    };
+    let upstream = host.and_then(|host| app.upstreams.status(&host));
+    let result = match (&app.routing, &upstream) {
+        (None, _) => Err(eyre!("No routing provider configured")),
+        (Some(_), Some(status)) if status.circuit == CircuitState::Open => {
+            Err(eyre!("{} is failing, its circuit is open", status.host))
+        }
+        (Some(_), _) => Ok(()),
+    };
+    DependencyStatus {
+        name: "routing",
+        required: app.config.required.routing,
+        healthy: result.is_ok(),
+        latency_ms: None,
+        error: result.err().map(|e| e.to_string()),
+        upstream,
+    }
+}
+
+/// Check every dependency, the database and geocoder at once
+pub async fn dependencies(app: &App) -> Vec<DependencyStatus> {
+    let (database, geocoder) = join!(check_database(app), check_geocoder(app));
+    vec![database, geocoder, check_routing(app)]
+}
+
+/// Whether every required dependency is healthy
+pub fn is_ready(dependencies: &[DependencyStatus]) -> bool {
+    dependencies
+        .iter()
+        .all(|dependency| dependency.healthy || !dependency.required)
+}
+
+/// The latest migration applied to the database, None if none have been
+pub async fn migration_version(app: &App) -> Result<Option<i64>> {
+    Ok(sqlx::query_scalar::<_, Option<i64>>(
+        "select max(version) from _sqlx_migrations where success",
+    )
+    .fetch_one(&app.db)
+    .await?)
+}
+
+/// Fail if a required dependency is down, so a broken deployment doesn't start, and warn about the rest
+pub async fn check_startup(app: &App) -> Result<()> {
+    let mut failed: Vec<String> = Vec::new();
+    for dependency in dependencies(app).await {
+        let error = dependency.error.unwrap_or_default();
+        match (dependency.healthy, dependency.required) {
+            (true, _) => {}
+            (false, true) => failed.push(format!("{}: {error}", dependency.name)),
+            (false, false) => warn!(
+                "{} unavailable, continuing without it: {error}",
+                dependency.name
+            ),
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Required dependencies unavailable:\n  {}",
+            failed.join("\n  ")
+        ))
+    }
+}
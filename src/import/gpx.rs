@@ -3,9 +3,10 @@ use color_eyre::eyre::Result;
 use crate::ride_geo::{BoundingBox, Distance};
 
 use geojson::FeatureCollection;
-use geojson::{Feature, Geometry};
+use geojson::{Feature, Geometry, Position};
 use gpx::Gpx;
 use gpx::Track;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tracing::info;
 
 use crate::types::feature::FeatureProperties;
@@ -39,24 +40,80 @@ impl AsRideFeature for Track {
         }
 
         let bounding_box = mls.bounding_box();
+        // Build positions ourselves rather than converting mls, as geo_types drops elevation
+        let lines: Vec<Vec<Position>> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .points
+                    .iter()
+                    .map(|waypoint| {
+                        let point = waypoint.point();
+                        match waypoint.elevation {
+                            Some(elevation) => vec![point.x(), point.y(), elevation],
+                            None => vec![point.x(), point.y()],
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
         let geom = Geometry {
             bbox: bounding_box.to_owned(),
-            value: (&mls).into(),
+            value: geojson::Value::MultiLineString(lines),
             foreign_members: None,
         };
         let distance = geom.distance();
-        return Some(Feature {
+        let coord_times: Vec<Vec<Option<String>>> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                segment
+                    .points
+                    .iter()
+                    .map(|waypoint| {
+                        waypoint
+                            .time
+                            .and_then(|time| OffsetDateTime::from(time).format(&Rfc3339).ok())
+                    })
+                    .collect()
+            })
+            .collect();
+        let has_times = coord_times.iter().flatten().any(|time| time.is_some());
+        Some(Feature {
             bbox: bounding_box.to_owned(),
             geometry: Some(geom),
             properties: Some(
                 FeatureProperties {
                     distance,
                     name: self.name.clone(),
+                    coord_times: has_times.then_some(coord_times),
                 }
                 .try_into()
                 .expect("Shouldnt fail json conversion"),
             ),
             ..Default::default()
-        });
+        })
+    }
+}
+
+pub trait RideTime {
+    /// When the ride was ridden, from the gpx metadata or else the first recorded point
+    fn ride_time(&self) -> Option<OffsetDateTime>;
+}
+
+impl RideTime for Gpx {
+    fn ride_time(&self) -> Option<OffsetDateTime> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.time)
+            .or_else(|| {
+                self.tracks
+                    .iter()
+                    .flat_map(|track| track.segments.iter())
+                    .flat_map(|segment| segment.points.iter())
+                    .find_map(|waypoint| waypoint.time)
+            })
+            .map(OffsetDateTime::from)
     }
 }
@@ -1,39 +1,103 @@
-#![feature(async_closure)]
 #![feature(iter_map_windows)]
 #![feature(iter_intersperse)]
 
+mod backfill;
+mod cache;
 mod clients;
+mod clubs;
+mod config;
+mod coverage;
+mod dem;
+mod drive_times;
+mod geocoder;
+mod health;
 mod import;
 mod net;
+mod origins;
+mod osm_index;
 mod ride;
+mod ride_format;
 mod ride_geo;
+mod ride_gradient;
 mod ride_processing;
+mod ride_profile;
+mod routing;
+mod stats;
+#[cfg(test)]
+mod stub_server;
+mod tiles;
 mod types;
+mod upstream;
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{Multipart, Path, Query},
-    http::StatusCode,
-    routing::{delete, get, post},
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use clients::{get_db_pool, DB_POOL, GMAPS, REQWEST};
+use clients::{App, AppState};
+use clubs::{
+    accept_invite, club_members, create_club, invite_member, remove_member, user_clubs,
+    user_invites,
+};
 use color_eyre::eyre::eyre;
+use config::Config;
+use coverage::{refresh_coverage, user_coverage};
+use drive_times::{prefilter_drive_time, ride_drive_times, within_drive_time};
 use futures::stream::TryStreamExt;
 use futures::{stream, StreamExt};
-use geojson::FeatureCollection;
-use google_maps::GoogleMapsClient;
-use net::response::{ResponseError, Result};
+use geo_types::Point;
+use geocoder::{search_point, Geocoder};
+use geojson::{FeatureCollection, GeoJson};
+use health::{check_startup, dependencies, is_ready, migration_version};
+use net::{
+    query::RideDetailQuery,
+    response::{ResponseError, Result},
+    user::{AdminUser, CurrentUser},
+};
+use origins::{
+    default_origin, delete_origin, locate_origin, save_origin, user_origin, user_origins,
+};
 use ride::create_ride;
-use ride_processing::{aggregate_surface, nominatim_get_place, process_ride};
-use sqlx::postgres::PgPoolOptions;
+use ride_format::{encoded_polylines, flatgeobuf, topo_json};
+use ride_geo::{
+    zoom_tolerance, DistanceIndex, RemoveTrackTimes, SimplifyLines, TrackLines, TrackPositions,
+    TrackTimes,
+};
+use ride_gradient::gradient_analysis;
+use ride_processing::{
+    aggregate_surface, process_ride, reconcile, road_summary, way_at, way_details,
+};
+use ride_profile::ride_profile;
+use sqlx::types::Json as SqlJson;
+use stats::riding_stats;
+use tiles::{encode_tile, TileFeature, TileId, TileValue};
+use time::OffsetDateTime;
 use tower_http::cors::CorsLayer;
-use tracing::{info, instrument};
-use types::dto::{self, geom::PartialLatLng};
+use tracing::{info, instrument, warn};
+use types::dto::{
+    self,
+    coverage::CoverageQuery,
+    drive_time::DriveTimeQuery,
+    geocode::{GeocodeQuery, GeocodedPlace},
+    geom::{GeometryFormat, PartialLatLng, SimplifyAlgorithm},
+    locate::LocateQuery,
+    nominatim::{Address, NominatimDetailsPlace},
+    origin::SaveOrigin,
+    profile::ProfileQuery,
+    stats::StatsQuery,
+    status::{Readiness, Status},
+    upstream::UpstreamStatus,
+};
 use types::model;
 
-use crate::{clients::NOMINATIM_URL, import::gpx::AsRideFeatureCollection};
+use crate::import::gpx::{AsRideFeatureCollection, RideTime};
+
+/// Below this zoom, tiles are drawn from rides' preview geometry rather than their full geometry
+const TILE_PREVIEW_MAX_ZOOM: u32 = 10;
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
@@ -41,57 +105,210 @@ async fn main() -> color_eyre::Result<()> {
     // initialize tracing
     tracing_subscriber::fmt::init();
 
-    init_db().await?;
-    init_google_maps()?;
-    NOMINATIM_URL
-        .set(std::env::var("EXPEDITION_NOMINATIM_URL")?)
-        .unwrap();
-    init_reqwest_client()?;
+    let config = Config::load()?;
+    let bind = config.server.bind;
+    let state = App::new(config).await?;
+    check_startup(&state).await?;
+    tokio::spawn(backfill::run(state.clone()));
 
-    // build our application with a route
-    let app = Router::new()
+    info!("Running on {bind}");
+
+    axum::Server::bind(&bind)
+        .serve(router(state).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+/// Every route, serving from the state, separate from main so instances can be built with their own config
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(get_status))
         .route("/gpx", post(import_gpx))
+        .route("/geocode", get(geocode))
         .route("/rides", get(list_rides))
         .route("/rides/:id", get(get_ride_by_id))
         .route("/rides/:id", delete(delete_ride_by_id))
-        .layer(CorsLayer::permissive());
+        .route("/rides/:id/locate", get(locate_on_ride))
+        .route("/rides/:id/profile", get(get_ride_profile))
+        .route("/tiles/:z/:x/:y", get(get_tile))
+        .route("/me/coverage", get(get_coverage))
+        .route("/me/origins", get(list_origins).post(create_origin))
+        .route(
+            "/me/origins/:id",
+            put(update_origin).delete(delete_saved_origin),
+        )
+        .route("/me/clubs", get(list_clubs).post(start_club))
+        .route("/me/invites", get(list_invites))
+        .route(
+            "/me/invites/:id",
+            post(accept_club_invite).delete(decline_club_invite),
+        )
+        .route("/clubs/:id/members", post(invite_club_member))
+        .route("/clubs/:id/members/:user_id", delete(remove_club_member))
+        .route("/stats", get(get_stats))
+        .route("/admin/upstreams", get(get_upstreams))
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
 
-    info!("Running on port 3000");
+/// The process is up, for liveness probes
+async fn healthz() -> &'static str {
+    "ok"
+}
 
-    // run our app with hyper, listening globally on port 3000
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await?;
+/// Whether every required dependency is up, for readiness probes, responding 503 if not
+async fn readyz(State(app): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let dependencies = dependencies(&app).await;
+    let ready = is_ready(&dependencies);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            dependencies,
+        }),
+    )
+}
 
-    Ok(())
+/// Build and migration versions, and each dependency's latency, latest error and circuit state
+async fn get_status(State(app): State<AppState>, _admin: AdminUser) -> Json<Status> {
+    let dependencies = dependencies(&app).await;
+    let migration_version = migration_version(&app).await.unwrap_or_else(|e| {
+        warn!("Couldn't read migration version: {e:#}");
+        None
+    });
+    Json(Status {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: option_env!("EXPEDITION_GIT_SHA").filter(|sha| !sha.is_empty()),
+        migration_version,
+        ready: is_ready(&dependencies),
+        dependencies,
+    })
 }
 
-async fn init_db() -> color_eyre::Result<()> {
-    let db_uri = std::env::var("DATABASE_URL")?;
-    info!("Connecting to db");
-    let db_pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&db_uri)
-        .await?;
-    DB_POOL.set(db_pool).unwrap();
-    info!("Connected");
-    Ok(())
+/// The Accept-Language header, for geocoders to name places in the user's language
+fn accept_language(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|language| language.to_str().ok())
 }
 
-fn init_google_maps() -> color_eyre::Result<()> {
-    let google_api_key = std::env::var("EXPEDITION_GOOGLE_API_KEY")?;
-    let google_maps_client = GoogleMapsClient::new(&google_api_key);
-    GMAPS.set(google_maps_client).unwrap();
-    Ok(())
+async fn geocode(
+    State(app): State<AppState>,
+    Query(query): Query<GeocodeQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<GeocodedPlace>>> {
+    let filters = query
+        .filters()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
+    Ok(Json(
+        app.geocoder
+            .search(&query.q, &filters, accept_language(&headers))
+            .await?,
+    ))
+}
+
+/// The origin from its coordinates, or failing that one of the user's saved origins, or a search for its free text.
+/// Without any of them, the user's default origin if they have one.
+async fn resolve_origin(
+    app: &App,
+    origin: PartialLatLng,
+    user: Option<&CurrentUser>,
+) -> Result<Option<Point>> {
+    let (text, origin_id) = (origin.origin.clone(), origin.origin_id);
+    let has_coordinates = origin.lat.is_some() && origin.lon.is_some();
+    if let Some(point) = Option::<Point>::from(origin) {
+        return Ok(Some(point));
+    }
+    if has_coordinates {
+        Err(ResponseError::bad_request("lat or lon is out of range"))?;
+    }
+    let user_id = user.map(|CurrentUser(user_id)| user_id.as_str());
+    match (origin_id, text, user_id) {
+        (Some(_), _, None) => Err(ResponseError::unauthorized("Not logged in"))?,
+        (Some(origin_id), _, Some(user_id)) => Ok(Some(
+            user_origin(&app.db, user_id, origin_id)
+                .await?
+                .ok_or(ResponseError::not_found("No saved origin with this id"))?,
+        )),
+        (None, Some(text), _) => Ok(Some(
+            search_point(&app.geocoder, &app.search_cache, &text)
+                .await?
+                .ok_or(ResponseError::bad_request("No place matches origin"))?,
+        )),
+        (None, None, Some(user_id)) => Ok(default_origin(&app.db, user_id).await?),
+        (None, None, None) => Ok(None),
+    }
+}
+
+async fn list_origins(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+) -> Result<Json<Vec<dto::origin::Origin>>> {
+    Ok(Json(user_origins(&app.db, &user_id).await?))
+}
+
+async fn create_origin(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Json(origin): Json<SaveOrigin>,
+) -> Result<Json<dto::origin::Origin>> {
+    let point = locate_origin(&app.geocoder, &app.search_cache, &origin)
+        .await?
+        .ok_or(ResponseError::bad_request("Couldn't find origin's address"))?;
+    let saved = save_origin(&app.db, &user_id, None, origin, point)
+        .await?
+        .ok_or(eyre!("Origin wasn't saved"))?;
+    Ok(Json(saved))
 }
 
-fn init_reqwest_client() -> color_eyre::Result<()> {
-    let client = reqwest::Client::new();
-    REQWEST.set(client).unwrap();
+async fn update_origin(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path(origin_id): Path<i64>,
+    Json(origin): Json<SaveOrigin>,
+) -> Result<Json<dto::origin::Origin>> {
+    let point = locate_origin(&app.geocoder, &app.search_cache, &origin)
+        .await?
+        .ok_or(ResponseError::bad_request("Couldn't find origin's address"))?;
+    let saved = save_origin(&app.db, &user_id, Some(origin_id), origin, point)
+        .await?
+        .ok_or(ResponseError::not_found("No saved origin with this id"))?;
+    Ok(Json(saved))
+}
+
+async fn delete_saved_origin(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path(origin_id): Path<i64>,
+) -> Result<()> {
+    if !delete_origin(&app.db, &user_id, origin_id).await? {
+        Err(ResponseError::not_found("No saved origin with this id"))?;
+    }
     Ok(())
 }
 
-async fn list_rides(Query(origin): Query<PartialLatLng>) -> Result<Json<Vec<dto::ride::ListRide>>> {
+async fn list_rides(
+    State(app): State<AppState>,
+    user: Option<CurrentUser>,
+    Query(origin): Query<PartialLatLng>,
+    Query(list_query): Query<dto::ride::ListRideQuery>,
+    Query(drive_time_query): Query<DriveTimeQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<dto::ride::ListRide>>> {
+    list_query
+        .validate()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
+    drive_time_query
+        .validate()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
     let rides = sqlx::query_as!(
         model::ride::QueryRide,
         r#"select 
@@ -99,30 +316,66 @@ async fn list_rides(Query(origin): Query<PartialLatLng>) -> Result<Json<Vec<dto:
         name,
         total_distance,
         null as "ways: _",
+        null as "unmatched: _",
         null as "geo_json: _",
+        case when $1 then preview_geo_json end as "preview_geo_json: _",
         jsonb_path_query(geo_json, '$[*].features ? (@.id == "start").geometry.coordinates') as "start_point: _",
         jsonb_path_query(geo_json, '$[*].features ? (@.id == "end").geometry.coordinates') as "end_point: _"
-        from rides"#
+        from rides"#,
+        list_query.includes("preview_geometry")
     )
-    .fetch_all(get_db_pool()?)
+    .fetch_all(&app.db)
     .await?;
 
-    let rides = stream::iter(rides.into_iter())
-        .map(|ride| {
-            let origin = origin.clone();
-            async move {
-                let processed_ride = process_ride(ride, origin.into()).await?;
-
-                Result::<dto::ride::ListRide>::Ok(dto::ride::ListRide {
-                    id: processed_ride.id,
-                    name: processed_ride.name,
-                    total_distance: processed_ride.total_distance,
-                    start_address: processed_ride.start_address.into(),
-                    end_address: processed_ride.end_address.into(),
-                    time_from_origin_to_start: processed_ride.time_from_origin_to_start,
-                    time_from_end_to_origin: processed_ride.time_from_end_to_origin,
-                })
-            }
+    let origin = resolve_origin(&app, origin, user.as_ref()).await?;
+    let rides = match (origin, list_query.max_drive_time) {
+        (_, Some(_)) if app.routing.is_none() => Err(ResponseError::bad_request(
+            "max_drive_time needs a routing provider",
+        ))?,
+        (Some(origin), Some(max_drive_time)) => {
+            prefilter_drive_time(app.routing.as_ref(), origin, max_drive_time, rides).await?
+        }
+        (None, Some(_)) => Err(ResponseError::bad_request("max_drive_time needs an origin"))?,
+        _ => rides,
+    };
+    let times = ride_drive_times(
+        app.routing.as_ref(),
+        &app.drive_time_cache,
+        origin,
+        &drive_time_query,
+        &rides,
+    )
+    .await?;
+    let mut rides_with_times: Vec<_> = rides.into_iter().zip(times).collect();
+    if let Some(max_drive_time) = list_query.max_drive_time {
+        rides_with_times = within_drive_time(rides_with_times, max_drive_time);
+    }
+    let language = accept_language(&headers);
+    let geocoder = &app.geocoder;
+    let rides = stream::iter(rides_with_times)
+        .map(|(ride, times)| async move {
+            let processed_ride = process_ride(geocoder, ride, times, language).await?;
+
+            Result::<dto::ride::ListRide>::Ok(dto::ride::ListRide {
+                id: processed_ride.id,
+                name: processed_ride.name,
+                total_distance: processed_ride.total_distance,
+                start_label: processed_ride
+                    .start_address
+                    .as_ref()
+                    .and_then(Address::label),
+                end_label: processed_ride.end_address.as_ref().and_then(Address::label),
+                start_address: processed_ride.start_address.map(SqlJson),
+                end_address: processed_ride.end_address.map(SqlJson),
+                time_from_origin_to_start: processed_ride.time_from_origin_to_start,
+                time_from_end_to_origin: processed_ride.time_from_end_to_origin,
+                time_from_origin_to_start_pessimistic: processed_ride
+                    .time_from_origin_to_start_pessimistic,
+                time_from_end_to_origin_pessimistic: processed_ride
+                    .time_from_end_to_origin_pessimistic,
+                preview_geometry: processed_ride.preview_geo_json,
+                warnings: processed_ride.warnings,
+            })
         })
         .buffered(10)
         .try_collect::<Vec<dto::ride::ListRide>>()
@@ -132,9 +385,25 @@ async fn list_rides(Query(origin): Query<PartialLatLng>) -> Result<Json<Vec<dto:
 }
 
 async fn get_ride_by_id(
+    State(app): State<AppState>,
+    user: Option<CurrentUser>,
     Path(ride_id): Path<i64>,
-    Query(origin): Query<PartialLatLng>,
-) -> Result<Json<dto::ride::Ride>> {
+    query: RideDetailQuery,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let RideDetailQuery {
+        origin,
+        drive_time: drive_time_query,
+        gradient: gradient_query,
+        geometry: geometry_query,
+        simplify: simplify_query,
+    } = query;
+    gradient_query
+        .validate()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
+    drive_time_query
+        .validate()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
     // jsonb_path_query_array(ways, '$[0 to 9]') as "ways: sqlx_json<Vec<model::ride::RideWay>>",
     let option_ride = sqlx::query_as!(
         model::ride::QueryRide,
@@ -143,78 +412,451 @@ async fn get_ride_by_id(
         name,
         total_distance,
         geo_json as "geo_json: _",
+        null as "preview_geo_json: _",
         ways as "ways: _",
+        unmatched as "unmatched: _",
         jsonb_path_query(geo_json, '$[*].features ? (@.id == "start").geometry.coordinates') as "start_point: _",
         jsonb_path_query(geo_json, '$[*].features ? (@.id == "end").geometry.coordinates') as "end_point: _"
         from rides
         where id = $1"#,
         ride_id.into()
     )
-    .fetch_optional(get_db_pool()?)
+    .fetch_optional(&app.db)
     .await?;
     let query_ride = Arc::new(option_ride.ok_or(ResponseError::not_found("No ride with this id"))?);
-    let ways: Vec<dto::ride::RideWay> = stream::iter(
-        query_ride
-            .clone()
-            .ways
-            .clone()
-            .ok_or(eyre!("No ways!"))?
+    let format = geometry_query.format(
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok()),
+    );
+    //FlatGeobuf is binary so can't carry the ride's metadata, skip looking it up
+    if format == Some(GeometryFormat::FlatGeobuf) {
+        let mut geo_json = query_ride
+            .geo_json
+            .as_ref()
+            .ok_or(eyre!("No geo_json!"))?
             .0
-            .into_iter(),
-    )
-    .map(|way| async move {
-        let mut place = nominatim_get_place("W", way.osm_id).await?;
-        place.extratags.surface = place
-            .extratags
-            .surface
-            .map(|s| aggregate_surface(&s).to_string());
-        Result::<dto::ride::RideWay>::Ok(dto::ride::RideWay {
+            .clone();
+        geo_json.remove_track_times();
+        let fgb = flatgeobuf(&query_ride.name, &geo_json)?;
+        return Ok(([(header::CONTENT_TYPE, "application/flatgeobuf")], fgb).into_response());
+    }
+    let model_ways = query_ride.ways.clone().ok_or(eyre!("No ways!"))?.0;
+    let roads = road_summary(&model_ways);
+    //Rides imported before unmatched stretches were stored have none
+    let unmatched = query_ride
+        .unmatched
+        .clone()
+        .map(|unmatched| unmatched.0)
+        .unwrap_or_default();
+    let reconciliation = reconcile(&model_ways, &unmatched, &query_ride.total_distance);
+    //Rides can be on the same way several times, only look each one up once
+    let mut osm_ids: Vec<u64> = model_ways.iter().map(|way| way.osm_id).collect();
+    osm_ids.sort_unstable();
+    osm_ids.dedup();
+    //Ways whose details can't be looked up are left without them, rather than failing the whole ride
+    let app = &*app;
+    let places: HashMap<u64, NominatimDetailsPlace> = stream::iter(osm_ids)
+        .map(|osm_id| async move { (osm_id, way_details(app, osm_id).await) })
+        .buffered(10)
+        .filter_map(|(osm_id, place)| async move {
+            match place {
+                Ok(mut place) => {
+                    place.extratags.surface = place
+                        .extratags
+                        .surface
+                        .map(|s| aggregate_surface(&s).to_string());
+                    Some((osm_id, place))
+                }
+                Err(e) => {
+                    warn!("Couldn't get details of way {osm_id}: {e:#}");
+                    None
+                }
+            }
+        })
+        .collect()
+        .await;
+    let ways: Vec<dto::ride::RideWay> = model_ways
+        .into_iter()
+        .map(|way| dto::ride::RideWay {
+            label: way.label(),
+            start_distance: way.start_distance,
+            end_distance: way.end_distance,
             distance: way.distance,
+            place: places.get(&way.osm_id).cloned(),
             points: way.points,
-            place,
         })
-    })
-    .buffered(10)
-    .try_collect()
-    .await?;
+        .collect();
+    let times = ride_drive_times(
+        app.routing.as_ref(),
+        &app.drive_time_cache,
+        resolve_origin(app, origin, user.as_ref()).await?,
+        &drive_time_query,
+        std::slice::from_ref(&*query_ride),
+    )
+    .await?
+    .pop()
+    .unwrap_or_default();
     let processed_ride = process_ride(
+        &app.geocoder,
         Arc::try_unwrap(query_ride).expect("Couldnt unwrap queryride"),
-        origin.into(),
+        times,
+        accept_language(&headers),
     )
     .await?;
+    let mut warnings = processed_ride.warnings;
+    let missing_places = ways.iter().filter(|way| way.place.is_none()).count();
+    if missing_places > 0 {
+        warnings.push(format!(
+            "Details unavailable for {missing_places} of {} ways",
+            ways.len()
+        ));
+    }
+    let geo_json = processed_ride.geo_json.ok_or(eyre!("No geo_json!"))?;
+    let surfaces: HashMap<usize, String> = ways
+        .iter()
+        .filter_map(|way| Some((way, way.place.as_ref()?.extratags.surface.clone()?)))
+        .flat_map(|(way, surface)| way.points.iter().map(move |p| (p.seq, surface.clone())))
+        .collect();
+    let gradient = gradient_analysis(
+        &DistanceIndex::new(geo_json.track_positions()),
+        &surfaces,
+        &gradient_query,
+    );
+    let mut geo_json = simplify_query.apply(geo_json);
+    geo_json.remove_track_times();
+    let geometry = match format {
+        None | Some(GeometryFormat::FlatGeobuf) => None,
+        Some(GeometryFormat::GeoJson) => Some(dto::ride::RideGeometry::GeoJson(geo_json)),
+        Some(GeometryFormat::Polyline5) => Some(dto::ride::RideGeometry::Polyline(
+            encoded_polylines(&geo_json, 5)?,
+        )),
+        Some(GeometryFormat::Polyline6) => Some(dto::ride::RideGeometry::Polyline(
+            encoded_polylines(&geo_json, 6)?,
+        )),
+        Some(GeometryFormat::TopoJson) => {
+            Some(dto::ride::RideGeometry::TopoJson(topo_json(&geo_json)))
+        }
+    };
     let ride = dto::ride::Ride {
         id: processed_ride.id,
         name: processed_ride.name,
         total_distance: processed_ride.total_distance,
-        geo_json: processed_ride.geo_json.ok_or(eyre!("No geo_json!"))?,
+        geometry,
         ways: ways.into(),
-        start_address: processed_ride.start_address.into(),
-        end_address: processed_ride.end_address.into(),
+        roads,
+        unmatched,
+        reconciliation,
+        start_label: processed_ride
+            .start_address
+            .as_ref()
+            .and_then(Address::label),
+        end_label: processed_ride.end_address.as_ref().and_then(Address::label),
+        start_address: processed_ride.start_address.map(SqlJson),
+        end_address: processed_ride.end_address.map(SqlJson),
         time_from_origin_to_start: processed_ride.time_from_origin_to_start,
         time_from_end_to_origin: processed_ride.time_from_end_to_origin,
+        time_from_origin_to_start_pessimistic: processed_ride.time_from_origin_to_start_pessimistic,
+        time_from_end_to_origin_pessimistic: processed_ride.time_from_end_to_origin_pessimistic,
+        gradient,
+        warnings,
     };
-    Ok(Json(ride))
+    Ok(Json(ride).into_response())
 }
 
-async fn delete_ride_by_id(Path(ride_id): Path<i64>) -> Result<()> {
-    let result = sqlx::query!(
-        r#"delete from rides 
+/// Vector tile of every ride's line, for an overview map of the whole catalogue.
+/// Every user's rides are drawn, as the ride list and ride details show them all too.
+async fn get_tile(
+    State(app): State<AppState>,
+    Path((z, x, y)): Path<(u32, u32, String)>,
+) -> Result<Response> {
+    let y = y
+        .strip_suffix(".mvt")
+        .ok_or(ResponseError::bad_request(
+            "Tiles are only available as .mvt",
+        ))?
+        .parse::<u32>()
+        .map_err(|_| ResponseError::bad_request("Invalid tile y"))?;
+    let tile = TileId { z, x, y };
+    if !tile.is_valid() {
+        Err(ResponseError::bad_request("Tile is out of range"))?;
+    }
+    let data = match app.tile_cache.get(&tile) {
+        Some(data) => data,
+        None => {
+            let [west, south, east, north] = tile.buffered_bounds();
+            let rides = sqlx::query_as!(
+                model::ride::TileRide,
+                r#"select
+                id,
+                name,
+                surface_class,
+                difficulty,
+                case when $5 then coalesce(preview_geo_json, geo_json) else geo_json end as "geo_json!: _"
+                from rides
+                where min_lon is null
+                or (min_lon <= $3 and max_lon >= $1 and min_lat <= $4 and max_lat >= $2)"#,
+                west,
+                south,
+                east,
+                north,
+                z < TILE_PREVIEW_MAX_ZOOM
+            )
+            .fetch_all(&app.db)
+            .await?;
+            let tolerance_m = zoom_tolerance(z as f64);
+            let features: Vec<TileFeature> = rides
+                .into_iter()
+                .map(|ride| {
+                    let mut properties = vec![
+                        ("id", TileValue::Uint(ride.id as u64)),
+                        ("name", TileValue::Str(ride.name)),
+                    ];
+                    if let Some(surface_class) = ride.surface_class {
+                        properties.push(("surface", TileValue::Str(surface_class)));
+                    }
+                    if let Some(difficulty) = ride.difficulty {
+                        properties.push(("difficulty", TileValue::Str(difficulty)));
+                    }
+                    TileFeature {
+                        id: ride.id as u64,
+                        lines: ride
+                            .geo_json
+                            .simplify_lines(tolerance_m, SimplifyAlgorithm::DouglasPeucker)
+                            .track_lines(),
+                        properties,
+                    }
+                })
+                .collect();
+            let data = encode_tile(&tile, "rides", &features);
+            app.tile_cache.insert(tile, data.clone());
+            data
+        }
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "application/vnd.mapbox-vector-tile")],
+        data,
+    )
+        .into_response())
+}
+
+async fn locate_on_ride(
+    State(app): State<AppState>,
+    Path(ride_id): Path<i64>,
+    Query(locate_query): Query<LocateQuery>,
+) -> Result<Json<dto::locate::Located>> {
+    let ride = sqlx::query!(
+        r#"select
+        geo_json as "geo_json: SqlJson<GeoJson>",
+        ways as "ways: SqlJson<Vec<model::ride::RideWay>>"
+        from rides
         where id = $1"#,
         ride_id
     )
-    .execute(get_db_pool()?)
-    .await?;
-    if result.rows_affected() == 0 {
-        Err(ResponseError::not_found("no ride with this id"))?;
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or(ResponseError::not_found("No ride with this id"))?;
+    let index = DistanceIndex::new(ride.geo_json.track_positions());
+    let (position, offset) = match locate_query {
+        LocateQuery {
+            distance_m: Some(distance),
+            ..
+        } => (
+            index
+                .position_at(distance)
+                .ok_or(ResponseError::bad_request(
+                    "distance_m is past the end of the ride",
+                ))?,
+            0.0,
+        ),
+        LocateQuery {
+            lat: Some(lat),
+            lon: Some(lon),
+            ..
+        } => index
+            .locate(&Point::new(lon, lat))
+            .ok_or(eyre!("Ride has no track"))?,
+        _ => Err(ResponseError::bad_request(
+            "Either distance_m or lat and lon are required",
+        ))?,
+    };
+    let way = way_at(&ride.ways, &position).map(|way| dto::locate::LocatedWay {
+        osm_id: way.osm_id,
+        name: way.name.clone(),
+        surface: way.surface.clone(),
+        distance: way.distance,
+    });
+    Ok(Json(dto::locate::Located {
+        point: position.point,
+        distance: position.distance,
+        offset,
+        elevation: position.elevation,
+        way,
+    }))
+}
+
+async fn get_ride_profile(
+    State(app): State<AppState>,
+    Path(ride_id): Path<i64>,
+    Query(profile_query): Query<ProfileQuery>,
+) -> Result<Json<Vec<dto::profile::ProfilePoint>>> {
+    let ride = sqlx::query!(
+        r#"select
+        geo_json as "geo_json: SqlJson<GeoJson>",
+        ways as "ways: SqlJson<Vec<model::ride::RideWay>>"
+        from rides
+        where id = $1"#,
+        ride_id
+    )
+    .fetch_optional(&app.db)
+    .await?
+    .ok_or(ResponseError::not_found("No ride with this id"))?;
+    let index = DistanceIndex::new(ride.geo_json.track_positions());
+    Ok(Json(ride_profile(
+        &index,
+        &ride.geo_json.track_times(),
+        &ride.ways,
+        profile_query.interval_m,
+    )))
+}
+
+async fn delete_ride_by_id(State(app): State<AppState>, Path(ride_id): Path<i64>) -> Result<()> {
+    let mut tx = app.db.begin().await?;
+    let deleted = sqlx::query!(
+        r#"delete from rides 
+        where id = $1
+        returning user_id"#,
+        ride_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ResponseError::not_found("no ride with this id"))?;
+    if let Some(user_id) = deleted.user_id {
+        refresh_coverage(&mut tx, &user_id).await?;
+    }
+    tx.commit().await?;
+    app.tile_cache.clear();
+    Ok(())
+}
+
+/// Limits, circuit state and totals for every external service called since startup
+async fn get_upstreams(
+    State(app): State<AppState>,
+    _admin: AdminUser,
+) -> Json<Vec<UpstreamStatus>> {
+    Json(app.upstreams.statuses())
+}
+
+async fn list_clubs(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+) -> Result<Json<Vec<dto::club::Club>>> {
+    Ok(Json(user_clubs(&app.db, &user_id).await?))
+}
+
+async fn start_club(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Json(club): Json<dto::club::NewClub>,
+) -> Result<Json<dto::club::Club>> {
+    if club.name.trim().is_empty() {
+        Err(ResponseError::bad_request("Club name can't be empty"))?;
+    }
+    Ok(Json(
+        create_club(&app.db, &user_id, club.name.trim()).await?,
+    ))
+}
+
+/// Invite a user to a club, they aren't a member and share nothing until they accept
+async fn invite_club_member(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path(club_id): Path<i64>,
+    Json(member): Json<dto::club::NewMember>,
+) -> Result<Json<dto::club::Club>> {
+    let club = invite_member(&app.db, &user_id, club_id, &member.user_id)
+        .await?
+        .ok_or(ResponseError::not_found("Not a member of this club"))?;
+    Ok(Json(club))
+}
+
+async fn list_invites(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+) -> Result<Json<Vec<dto::club::Club>>> {
+    Ok(Json(user_invites(&app.db, &user_id).await?))
+}
+
+async fn accept_club_invite(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path(club_id): Path<i64>,
+) -> Result<()> {
+    if !accept_invite(&app.db, &user_id, club_id).await? {
+        Err(ResponseError::not_found("No invite to this club"))?;
     }
     Ok(())
 }
 
-#[instrument(skip(multipart))]
+async fn decline_club_invite(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path(club_id): Path<i64>,
+) -> Result<()> {
+    if !remove_member(&app.db, &user_id, club_id, &user_id).await? {
+        Err(ResponseError::not_found("No invite to this club"))?;
+    }
+    Ok(())
+}
+
+async fn remove_club_member(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Path((club_id, member)): Path<(i64, String)>,
+) -> Result<()> {
+    if !remove_member(&app.db, &user_id, club_id, &member).await? {
+        Err(ResponseError::not_found("No such member of this club"))?;
+    }
+    Ok(())
+}
+
+async fn get_stats(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Query(stats_query): Query<StatsQuery>,
+) -> Result<Json<dto::stats::Stats>> {
+    let user_ids = match stats_query.club_id {
+        Some(club_id) => club_members(&app.db, &user_id, club_id)
+            .await?
+            .ok_or(ResponseError::not_found("Not a member of this club"))?,
+        None => vec![user_id],
+    };
+    Ok(Json(riding_stats(&app.db, &user_ids).await?))
+}
+
+async fn get_coverage(
+    State(app): State<AppState>,
+    CurrentUser(user_id): CurrentUser,
+    Query(coverage_query): Query<CoverageQuery>,
+) -> Result<Json<dto::coverage::Coverage>> {
+    coverage_query
+        .validate()
+        .map_err(|e| ResponseError::bad_request(e.to_string()))?;
+    Ok(Json(
+        user_coverage(&app.db, &user_id, &coverage_query).await?,
+    ))
+}
+
+#[instrument(skip(app, user, multipart))]
 #[axum::debug_handler]
-async fn import_gpx(mut multipart: Multipart) -> Result<()> {
+async fn import_gpx(
+    State(app): State<AppState>,
+    user: Option<CurrentUser>,
+    mut multipart: Multipart,
+) -> Result<()> {
     let mut ride_name_opt: Option<String> = None;
     let mut geo_feature_collection_opt: Option<FeatureCollection> = None;
+    let mut ridden_at_opt: Option<OffsetDateTime> = None;
     // let mut ride_name_opt: Option<String> = None;
     while let Some(field) = multipart.next_field().await? {
         let name = field.name().ok_or(ResponseError::internal_server_error(
@@ -225,11 +867,12 @@ async fn import_gpx(mut multipart: Multipart) -> Result<()> {
             "gpx" => {
                 let gpx_obj = gpx::read(field.text().await?.as_bytes())?;
                 geo_feature_collection_opt = Some(gpx_obj.as_ride_feature_collection()?);
+                ridden_at_opt = gpx_obj.ride_time();
             }
             _ => continue,
         }
     }
-    let ride_name = ride_name_opt.ok_or(ResponseError::with_status(
+    ride_name_opt.ok_or(ResponseError::with_status(
         StatusCode::BAD_REQUEST,
         "ride_name not provided",
     ))?;
@@ -238,6 +881,7 @@ async fn import_gpx(mut multipart: Multipart) -> Result<()> {
         "gpx not provided",
     ))?;
     let ride = create_ride(
+        &app,
         geo_feature_collection
             .features
             .iter()
@@ -253,18 +897,110 @@ async fn import_gpx(mut multipart: Multipart) -> Result<()> {
             .into_iter()
             .intersperse(" / ")
             .collect(),
+        user.map(|CurrentUser(user_id)| user_id),
+        ridden_at_opt.unwrap_or_else(OffsetDateTime::now_utc),
         geo_feature_collection,
     )
     .await?;
-    sqlx::query!(
-        r#"insert into rides (name, geo_json, total_distance, ways)
-        values ($1, $2, $3, $4)"#,
+    let mut tx = app.db.begin().await?;
+    let ride_id = sqlx::query_scalar!(
+        r#"insert into rides (name, user_id, ridden_at, geo_json, preview_geo_json, total_distance,
+            ways, unmatched, start_address, surface_class, difficulty, min_lon, min_lat, max_lon, max_lat)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+        returning id"#,
         ride.name,
+        ride.user_id,
+        ride.ridden_at,
         ride.geo_json as _,
+        ride.preview_geo_json as _,
         ride.total_distance,
         ride.ways as _,
+        ride.unmatched as _,
+        ride.start_address as _,
+        ride.surface_class,
+        ride.difficulty,
+        ride.min_lon,
+        ride.min_lat,
+        ride.max_lon,
+        ride.max_lat,
     )
-    .execute(get_db_pool()?)
+    .fetch_one(&mut *tx)
     .await?;
+    //Normalise the ways too, for querying across rides
+    sqlx::query!(
+        r#"insert into ride_ways (ride_id, seq, osm_id, name, highway, surface, region, distance)
+        select
+        rides.id,
+        (way->>'seq')::bigint,
+        (way->>'osm_id')::bigint,
+        way->>'name',
+        way->>'highway',
+        way->>'surface',
+        way->>'region',
+        (way->>'distance')::double precision
+        from rides cross join jsonb_array_elements(rides.ways) as way
+        where rides.id = $1"#,
+        ride_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if let Some(user_id) = &ride.user_id {
+        refresh_coverage(&mut tx, user_id).await?;
+    }
+    tx.commit().await?;
+    app.tile_cache.clear();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    /// Serve every route from an app whose database is never connected to,
+    /// for requests which are answered before it's needed
+    async fn serve() -> String {
+        let db = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/expedition")
+            .unwrap();
+        let state = App::with_db(Config::default(), db).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router(state).into_make_service()),
+        );
+        url
+    }
+
+    #[tokio::test]
+    async fn router_serves_routes() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+
+        let live = client.get(format!("{url}/healthz")).send().await.unwrap();
+        assert_eq!(live.status(), StatusCode::OK);
+        assert_eq!(live.text().await.unwrap(), "ok");
+
+        let tile = client
+            .get(format!("{url}/tiles/30/0/0.mvt"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(tile.status(), StatusCode::BAD_REQUEST);
+
+        let missing = client.get(format!("{url}/nowhere")).send().await.unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        let admin = client
+            .get(format!("{url}/admin/upstreams"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(admin.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1 +1,3 @@
+pub mod query;
 pub mod response;
+pub mod user;
@@ -0,0 +1,44 @@
+use axum::{async_trait, extract::FromRequestParts, extract::Query, http::request::Parts};
+use serde::de::DeserializeOwned;
+
+use crate::types::dto::{
+    drive_time::DriveTimeQuery,
+    geom::{GeometryQuery, PartialLatLng, SimplifyQuery},
+    gradient::GradientQuery,
+};
+
+use super::response::ResponseError;
+
+/// Every query parameter a ride's details take, read from the one query string.
+/// Each part is parsed on its own, as flattening them into one struct loses their numbers' types.
+pub struct RideDetailQuery {
+    pub origin: PartialLatLng,
+    pub drive_time: DriveTimeQuery,
+    pub gradient: GradientQuery,
+    pub geometry: GeometryQuery,
+    pub simplify: SimplifyQuery,
+}
+
+fn parse<T: DeserializeOwned>(parts: &Parts) -> Result<T, ResponseError> {
+    Query::try_from_uri(&parts.uri)
+        .map(|Query(query)| query)
+        .map_err(|rejection| ResponseError::bad_request(rejection.body_text()))
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RideDetailQuery
+where
+    S: Send + Sync,
+{
+    type Rejection = ResponseError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RideDetailQuery {
+            origin: parse(parts)?,
+            drive_time: parse(parts)?,
+            gradient: parse(parts)?,
+            geometry: parse(parts)?,
+            simplify: parse(parts)?,
+        })
+    }
+}
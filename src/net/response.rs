@@ -3,11 +3,12 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
-pub struct ResponseError(Response);
+//Boxed so results carrying it stay small
+pub struct ResponseError(Box<Response>);
 
 impl IntoResponse for ResponseError {
     fn into_response(self) -> Response {
-        self.0
+        *self.0
     }
 }
 
@@ -16,13 +17,13 @@ where
     E: Into<color_eyre::eyre::Error>,
 {
     fn from(value: E) -> Self {
-        Self(
+        Self(Box::new(
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Into::<color_eyre::eyre::Error>::into(value).to_string(),
             )
                 .into_response(),
-        )
+        ))
     }
 }
 
@@ -31,28 +32,44 @@ impl ResponseError {
     where
         (StatusCode, T): IntoResponse,
     {
-        ResponseError((status_code, data).into_response())
+        ResponseError(Box::new((status_code, data).into_response()))
     }
 
     pub fn internal_server_error<T>(data: T) -> Self
     where
         (StatusCode, T): IntoResponse,
     {
-        ResponseError((StatusCode::INTERNAL_SERVER_ERROR, data).into_response())
+        ResponseError(Box::new(
+            (StatusCode::INTERNAL_SERVER_ERROR, data).into_response(),
+        ))
     }
 
     pub fn not_found<T>(data: T) -> Self
     where
         (StatusCode, T): IntoResponse,
     {
-        ResponseError((StatusCode::NOT_FOUND, data).into_response())
+        ResponseError(Box::new((StatusCode::NOT_FOUND, data).into_response()))
+    }
+
+    pub fn unauthorized<T>(data: T) -> Self
+    where
+        (StatusCode, T): IntoResponse,
+    {
+        ResponseError(Box::new((StatusCode::UNAUTHORIZED, data).into_response()))
+    }
+
+    pub fn forbidden<T>(data: T) -> Self
+    where
+        (StatusCode, T): IntoResponse,
+    {
+        ResponseError(Box::new((StatusCode::FORBIDDEN, data).into_response()))
     }
 
     pub fn bad_request<T>(data: T) -> Self
     where
         (StatusCode, T): IntoResponse,
     {
-        ResponseError((StatusCode::BAD_REQUEST, data).into_response())
+        ResponseError(Box::new((StatusCode::BAD_REQUEST, data).into_response()))
     }
 }
 
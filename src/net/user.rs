@@ -0,0 +1,48 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+
+use crate::clients::AppState;
+
+use super::response::ResponseError;
+
+/// Header the auth proxy in front of the server sets to the Kratos identity of a logged in user
+const USER_ID_HEADER: &str = "x-user-id";
+
+/// The logged in user making the request
+pub struct CurrentUser(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = ResponseError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user_id = parts
+            .headers
+            .get(USER_ID_HEADER)
+            .and_then(|user_id| user_id.to_str().ok())
+            .filter(|user_id| !user_id.is_empty())
+            .ok_or(ResponseError::unauthorized("Not logged in"))?;
+        Ok(CurrentUser(user_id.to_string()))
+    }
+}
+
+/// The logged in user making the request, who must be one of the configured admins
+pub struct AdminUser;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = ResponseError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let CurrentUser(user_id) = CurrentUser::from_request_parts(parts, state).await?;
+        if !state.is_admin(&user_id) {
+            return Err(ResponseError::forbidden("Not an admin"));
+        }
+        Ok(AdminUser)
+    }
+}
@@ -0,0 +1,133 @@
+use color_eyre::eyre::Result;
+use geo_types::Point;
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::{
+    geocoder::{search_point, AnyGeocoder, SearchCache},
+    types::dto::origin::{Origin, SaveOrigin},
+};
+
+pub async fn user_origins(pool: &PgPool, user_id: &str) -> Result<Vec<Origin>> {
+    Ok(sqlx::query_as!(
+        Origin,
+        r#"select id, name, address, lat, lon, is_default
+        from saved_origins
+        where user_id = $1
+        order by is_default desc, name"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?)
+}
+
+/// The user's origin with this id, or None if they have none by it
+pub async fn user_origin(pool: &PgPool, user_id: &str, origin_id: i64) -> Result<Option<Point>> {
+    let origin = sqlx::query!(
+        r#"select lat, lon from saved_origins where user_id = $1 and id = $2"#,
+        user_id,
+        origin_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(origin.map(|origin| Point::new(origin.lon, origin.lat)))
+}
+
+pub async fn default_origin(pool: &PgPool, user_id: &str) -> Result<Option<Point>> {
+    let origin = sqlx::query!(
+        r#"select lat, lon from saved_origins where user_id = $1 and is_default"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(origin.map(|origin| Point::new(origin.lon, origin.lat)))
+}
+
+/// Where to put an origin, from its coordinates or else its address.
+/// None if it has neither, or its address can't be found.
+pub async fn locate_origin(
+    geocoder: &AnyGeocoder,
+    cache: &SearchCache,
+    origin: &SaveOrigin,
+) -> Result<Option<Point>> {
+    match (origin.lat, origin.lon, &origin.address) {
+        (Some(lat), Some(lon), _) => Ok(Some(Point::new(lon, lat))),
+        (_, _, Some(address)) => search_point(geocoder, cache, address).await,
+        _ => Ok(None),
+    }
+}
+
+/// Save a new origin at the point, or replace the one with the given id.
+/// Returns None if the user has no origin with the id.
+#[instrument(skip(pool, origin, point))]
+pub async fn save_origin(
+    pool: &PgPool,
+    user_id: &str,
+    origin_id: Option<i64>,
+    origin: SaveOrigin,
+    point: Point,
+) -> Result<Option<Origin>> {
+    let mut tx = pool.begin().await?;
+    //Only one origin can be the default
+    if origin.is_default {
+        sqlx::query!(
+            r#"update saved_origins set is_default = false where user_id = $1 and is_default"#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    let saved = match origin_id {
+        Some(origin_id) => {
+            sqlx::query_as!(
+                Origin,
+                r#"update saved_origins
+                set name = $3, address = $4, lat = $5, lon = $6, is_default = $7
+                where user_id = $1 and id = $2
+                returning id, name, address, lat, lon, is_default"#,
+                user_id,
+                origin_id,
+                origin.name,
+                origin.address,
+                point.y(),
+                point.x(),
+                origin.is_default
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+        }
+        None => Some(
+            sqlx::query_as!(
+                Origin,
+                r#"insert into saved_origins (user_id, name, address, lat, lon, is_default)
+                values ($1, $2, $3, $4, $5, $6)
+                returning id, name, address, lat, lon, is_default"#,
+                user_id,
+                origin.name,
+                origin.address,
+                point.y(),
+                point.x(),
+                origin.is_default
+            )
+            .fetch_one(&mut *tx)
+            .await?,
+        ),
+    };
+    //Dropping the transaction without committing rolls back clearing the old default
+    if saved.is_some() {
+        tx.commit().await?;
+    }
+    Ok(saved)
+}
+
+/// Returns whether the user had an origin with the id
+pub async fn delete_origin(pool: &PgPool, user_id: &str, origin_id: i64) -> Result<bool> {
+    let deleted = sqlx::query!(
+        r#"delete from saved_origins where user_id = $1 and id = $2"#,
+        user_id,
+        origin_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(deleted.rows_affected() > 0)
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use color_eyre::eyre::Result;
+use geo_types::Point;
+use osmpbf::{Element, ElementReader};
+use rstar::{
+    primitives::{GeomWithData, Line},
+    RTree,
+};
+use tracing::{info, instrument};
+
+use crate::types::dto::nominatim::{ExtraTags, NominatimDetailsPlace, NominatimPlace};
+
+/// Ways further than this from a point, in metres, aren't considered to be under it
+const MAX_WAY_DISTANCE: f64 = 30.0;
+/// Metres per degree of latitude, for comparing distances in degrees
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// A highway way from the extract with the tags rides care about
+struct OsmWay {
+    id: u64,
+    name: Option<String>,
+    highway: String,
+    surface: Option<String>,
+}
+
+/// One section of a way between two of its nodes, tagged with the way's index
+type WaySegment = GeomWithData<Line<[f64; 2]>, usize>;
+
+/// Highway ways from a local OSM extract, indexed for finding the nearest way to a point without Nominatim
+pub struct OsmIndex {
+    ways: Vec<OsmWay>,
+    by_id: HashMap<u64, usize>,
+    segments: RTree<WaySegment>,
+}
+
+impl OsmIndex {
+    /// Read the highway ways from a .pbf extract.
+    /// Ways come after nodes in extracts, so it's read twice to keep only the nodes on highways.
+    #[instrument]
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut ways: Vec<OsmWay> = Vec::new();
+        let mut way_nodes: Vec<Vec<i64>> = Vec::new();
+        ElementReader::from_path(path)?.for_each(|element| {
+            let Element::Way(way) = element else {
+                return;
+            };
+            let tags: HashMap<&str, &str> = way.tags().collect();
+            let Some(highway) = tags.get("highway") else {
+                return;
+            };
+            ways.push(OsmWay {
+                id: way.id() as u64,
+                name: tags.get("name").map(|name| name.to_string()),
+                highway: highway.to_string(),
+                surface: tags.get("surface").map(|surface| surface.to_string()),
+            });
+            way_nodes.push(way.refs().collect());
+        })?;
+        let needed: HashSet<i64> = way_nodes.iter().flatten().copied().collect();
+        let mut nodes = HashMap::<i64, [f64; 2]>::with_capacity(needed.len());
+        ElementReader::from_path(path)?.for_each(|element| {
+            let (id, lon, lat) = match element {
+                Element::Node(node) => (node.id(), node.lon(), node.lat()),
+                Element::DenseNode(node) => (node.id(), node.lon(), node.lat()),
+                _ => return,
+            };
+            if needed.contains(&id) {
+                nodes.insert(id, [lon, lat]);
+            }
+        })?;
+        let segments: Vec<WaySegment> = way_nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, refs)| {
+                let nodes = &nodes;
+                refs.iter()
+                    .filter_map(move |id| nodes.get(id))
+                    .map_windows(move |[from, to]| GeomWithData::new(Line::new(**from, **to), i))
+            })
+            .collect();
+        info!(
+            "Indexed {} highway ways with {} segments",
+            ways.len(),
+            segments.len()
+        );
+        let by_id = ways
+            .iter()
+            .enumerate()
+            .map(|(i, way)| (way.id, i))
+            .collect();
+        Ok(OsmIndex {
+            ways,
+            by_id,
+            segments: RTree::bulk_load(segments),
+        })
+    }
+
+    /// The nearest highway way to the point, in the shape Nominatim's reverse geocoding gives, or None if there's none close
+    pub fn reverse_geocode(&self, point: &Point) -> Option<NominatimPlace> {
+        let (segment, distance_2) = self
+            .segments
+            .nearest_neighbor_iter_with_distance_2(&[point.x(), point.y()])
+            .next()?;
+        // Degrees of longitude shrink away from the equator, allow for the distance along them
+        let scale = point.y().to_radians().cos().max(f64::EPSILON);
+        let max_degrees = MAX_WAY_DISTANCE / METRES_PER_DEGREE / scale;
+        if distance_2 > max_degrees * max_degrees {
+            return None;
+        }
+        let way = &self.ways[segment.data];
+        Some(NominatimPlace {
+            osm_type: String::from("way"),
+            osm_id: way.id,
+            display_name: way.name.clone().unwrap_or_default(),
+            category: Some(String::from("highway")),
+            place_type: Some(way.highway.clone()),
+            name: way.name.clone(),
+            // The extract has no administrative boundaries to build an address from
+            address: None,
+            extratags: ExtraTags {
+                surface: way.surface.clone(),
+            },
+        })
+    }
+
+    /// A way's details, in the shape Nominatim's details endpoint gives
+    pub fn way(&self, osm_id: u64) -> Option<NominatimDetailsPlace> {
+        let way = &self.ways[*self.by_id.get(&osm_id)?];
+        Some(NominatimDetailsPlace {
+            osm_type: String::from("W"),
+            osm_id: way.id,
+            localname: way.name.clone().unwrap_or_default(),
+            extratags: ExtraTags {
+                surface: way.surface.clone(),
+            },
+        })
+    }
+}
@@ -1,15 +1,43 @@
+use std::collections::HashMap;
+
 use bigdecimal::BigDecimal;
-use geo_types::Point;
+use geo_types::{MultiPoint, Point};
 use geojson::{Feature, FeatureCollection, Geometry};
+use time::OffsetDateTime;
 
 use crate::{
-    ride_geo::{Distance, EndPoint, Points, StartPoint},
+    clients::App,
+    dem::fill_elevation,
+    geocoder::Geocoder,
+    ride_geo::{
+        simplify_to_budget, BoundingBox, Distance, DistanceIndex, EndPoint, Points,
+        RemoveTrackTimes, SimplifyLines, StartPoint, TrackLines, TrackPositions,
+    },
+    ride_gradient::gradient_analysis,
     ride_processing::ride_ways,
-    types::model::ride::Ride,
+    types::{
+        dto::{
+            geom::SimplifyAlgorithm,
+            gradient::{GradientAnalysis, GradientQuery},
+        },
+        model::ride::{Ride, RideWay, WayBreakdown},
+    },
 };
 use color_eyre::eyre::{eyre, Result};
 
-pub async fn create_ride(name: String, mut feature_collection: FeatureCollection) -> Result<Ride> {
+/// Most points kept in a ride's preview geometry
+const PREVIEW_MAX_POINTS: usize = 200;
+
+pub async fn create_ride(
+    app: &App,
+    name: String,
+    user_id: Option<String>,
+    ridden_at: OffsetDateTime,
+    mut feature_collection: FeatureCollection,
+) -> Result<Ride> {
+    if let Some(dem) = &app.dem {
+        fill_elevation(dem, &mut feature_collection).await?;
+    }
     let start_point = feature_collection
         .start_point()
         .ok_or(eyre!("No start point on geometry"))?;
@@ -22,17 +50,98 @@ pub async fn create_ride(name: String, mut feature_collection: FeatureCollection
     feature_collection
         .features
         .push(feature_point(String::from("end"), &end_point));
+    let start_address = app
+        .geocoder
+        .reverse(&start_point, None)
+        .await?
+        .address
+        .unwrap_or_default();
     let total_distance = BigDecimal::try_from(feature_collection.distance())?;
-    let ways = ride_ways(feature_collection.points(), &total_distance).await?;
+    let index = DistanceIndex::new(feature_collection.track_positions());
+    let WayBreakdown { ways, unmatched } = ride_ways(app, &index, &total_distance).await?;
+    let bounding_box = MultiPoint::from(feature_collection.points().collect::<Vec<Point>>())
+        .bounding_box()
+        .ok_or(eyre!("No bounding box for geometry"))?;
+    let surfaces: HashMap<usize, String> = ways
+        .iter()
+        .filter_map(|way| Some((way, way.surface.clone()?)))
+        .flat_map(|(way, surface)| way.points.iter().map(move |p| (p.seq, surface.clone())))
+        .collect();
+    let gradient = gradient_analysis(&index, &surfaces, &GradientQuery::default());
+    let preview = preview(&feature_collection);
     Ok(Ride {
         id: None,
         name,
+        user_id,
+        ridden_at,
         geo_json: sqlx::types::Json(feature_collection.into()),
+        preview_geo_json: sqlx::types::Json(preview.into()),
         total_distance,
+        start_address: sqlx::types::Json(start_address),
+        surface_class: surface_class(&ways),
+        difficulty: difficulty(&ways, gradient.as_ref()),
+        min_lon: bounding_box[0],
+        min_lat: bounding_box[1],
+        max_lon: bounding_box[2],
+        max_lat: bounding_box[3],
         ways: sqlx::types::Json(ways),
+        unmatched: sqlx::types::Json(unmatched),
     })
 }
 
+/// A ride's geometry simplified for drawing many rides at once
+pub fn preview<T>(geo: &T) -> T
+where
+    T: SimplifyLines + TrackLines + RemoveTrackTimes + Clone,
+{
+    let mut preview =
+        simplify_to_budget(geo, PREVIEW_MAX_POINTS, SimplifyAlgorithm::DouglasPeucker);
+    preview.remove_track_times();
+    preview
+}
+
+/// The aggregated surface covering the most distance
+fn surface_class(ways: &[RideWay]) -> Option<String> {
+    let mut distances = HashMap::<&str, f64>::new();
+    ways.iter().for_each(|way| {
+        if let Some(surface) = &way.surface {
+            *distances.entry(surface).or_insert(0.0) += way.distance;
+        }
+    });
+    distances
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(surface, _)| surface.to_string())
+}
+
+/// Rough difficulty from how much of the ride is dirt, and how many steep sections are on dirt
+fn difficulty(ways: &[RideWay], gradient: Option<&GradientAnalysis>) -> Option<String> {
+    let total: f64 = ways.iter().map(|way| way.distance).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let dirt: f64 = ways
+        .iter()
+        .filter(|way| way.surface.as_deref() == Some("dirt"))
+        .map(|way| way.distance)
+        .sum();
+    let dirt_share = dirt / total;
+    let steep_dirt = gradient.map_or(0, |g| {
+        g.steep_sections
+            .iter()
+            .filter(|s| s.surface.as_deref() == Some("dirt"))
+            .count()
+    });
+    let difficulty = if steep_dirt >= 3 || (dirt_share >= 0.5 && steep_dirt >= 1) {
+        "hard"
+    } else if dirt_share >= 0.2 || steep_dirt >= 1 {
+        "moderate"
+    } else {
+        "easy"
+    };
+    Some(difficulty.to_string())
+}
+
 fn feature_point(id: String, point: &Point) -> Feature {
     Feature {
         id: Some(geojson::feature::Id::String(id)),
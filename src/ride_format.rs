@@ -0,0 +1,165 @@
+use color_eyre::eyre::{eyre, Result};
+use flatgeobuf::{FgbWriter, GeometryType};
+use geojson::{Feature, GeoJson, Position};
+use geozero::GeozeroDatasource;
+use serde_json::{json, Value};
+
+use crate::ride_geo::{Points, TrackLines};
+
+/// Number of distinct positions along each axis of the ride's bounding box in TopoJSON output
+const TOPOJSON_QUANTIZATION: f64 = 1e5;
+
+/// Encode each of the ride's track lines as a Google encoded polyline
+pub fn encoded_polylines(geo_json: &GeoJson, precision: u32) -> Result<Vec<String>> {
+    geo_json
+        .track_lines()
+        .into_iter()
+        .map(|line| polyline::encode_coordinates(line, precision).map_err(|e| eyre!(e)))
+        .collect()
+}
+
+/// Quantizes positions onto an integer grid over a bounding box, as described by a TopoJSON transform
+struct Quantizer {
+    translate: (f64, f64),
+    scale: (f64, f64),
+}
+
+impl Quantizer {
+    fn new(geo_json: &GeoJson) -> Self {
+        let (mut min, mut max) = ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN));
+        geo_json.points().for_each(|p| {
+            min = (min.0.min(p.x()), min.1.min(p.y()));
+            max = (max.0.max(p.x()), max.1.max(p.y()));
+        });
+        let scale = |min: f64, max: f64| match (max - min) / (TOPOJSON_QUANTIZATION - 1.0) {
+            s if s > 0.0 => s,
+            _ => 1.0,
+        };
+        Quantizer {
+            translate: min,
+            scale: (scale(min.0, max.0), scale(min.1, max.1)),
+        }
+    }
+
+    fn quantize(&self, position: &Position) -> (i64, i64) {
+        (
+            ((position[0] - self.translate.0) / self.scale.0).round() as i64,
+            ((position[1] - self.translate.1) / self.scale.1).round() as i64,
+        )
+    }
+
+    /// A delta encoded arc, as TopoJSON expects when quantized
+    fn arc(&self, line: &[Position]) -> Value {
+        let mut previous = (0, 0);
+        line.iter()
+            .map(|position| {
+                let (x, y) = self.quantize(position);
+                let delta = json!([x - previous.0, y - previous.1]);
+                previous = (x, y);
+                delta
+            })
+            .collect()
+    }
+
+    fn transform(&self) -> Value {
+        json!({
+            "scale": [self.scale.0, self.scale.1],
+            "translate": [self.translate.0, self.translate.1],
+        })
+    }
+}
+
+/// Convert a geometry to its TopoJSON form, adding any lines to arcs
+fn topo_json_geometry(
+    value: &geojson::Value,
+    quantizer: &Quantizer,
+    arcs: &mut Vec<Value>,
+) -> Value {
+    let mut push_arc = |line: &[Position]| {
+        arcs.push(quantizer.arc(line));
+        arcs.len() - 1
+    };
+    match value {
+        geojson::Value::Point(position) => {
+            let (x, y) = quantizer.quantize(position);
+            json!({ "type": "Point", "coordinates": [x, y] })
+        }
+        geojson::Value::MultiPoint(positions) => json!({
+            "type": "MultiPoint",
+            "coordinates": positions
+                .iter()
+                .map(|p| {
+                    let (x, y) = quantizer.quantize(p);
+                    json!([x, y])
+                })
+                .collect::<Vec<Value>>(),
+        }),
+        geojson::Value::LineString(line) => {
+            json!({ "type": "LineString", "arcs": [push_arc(line)] })
+        }
+        geojson::Value::MultiLineString(lines) => json!({
+            "type": "MultiLineString",
+            "arcs": lines
+                .iter()
+                .map(|line| json!([push_arc(line)]))
+                .collect::<Vec<Value>>(),
+        }),
+        geojson::Value::GeometryCollection(geoms) => json!({
+            "type": "GeometryCollection",
+            "geometries": geoms
+                .iter()
+                .map(|g| topo_json_geometry(&g.value, quantizer, arcs))
+                .collect::<Vec<Value>>(),
+        }),
+        // Rides never have polygons
+        geojson::Value::Polygon(_) | geojson::Value::MultiPolygon(_) => json!({ "type": null }),
+    }
+}
+
+/// Convert the ride to a quantized TopoJSON topology, with its features under the "ride" object
+pub fn topo_json(geo_json: &GeoJson) -> Value {
+    let features: Vec<&Feature> = match geo_json {
+        GeoJson::FeatureCollection(fc) => fc.features.iter().collect(),
+        GeoJson::Feature(feat) => vec![feat],
+        GeoJson::Geometry(_) => Vec::new(),
+    };
+    let quantizer = Quantizer::new(geo_json);
+    let mut arcs = Vec::<Value>::new();
+    let geometries: Vec<Value> = features
+        .into_iter()
+        .map(|feature| {
+            let mut geometry = feature
+                .geometry
+                .as_ref()
+                .map_or(json!({ "type": null }), |geom| {
+                    topo_json_geometry(&geom.value, &quantizer, &mut arcs)
+                });
+            if let Some(id) = &feature.id {
+                geometry["id"] = serde_json::to_value(id).unwrap_or(Value::Null);
+            }
+            if let Some(properties) = &feature.properties {
+                geometry["properties"] = Value::Object(properties.clone());
+            }
+            geometry
+        })
+        .collect();
+    json!({
+        "type": "Topology",
+        "transform": quantizer.transform(),
+        "objects": {
+            "ride": { "type": "GeometryCollection", "geometries": geometries },
+        },
+        "arcs": arcs,
+    })
+}
+
+/// Encode the ride's features as a FlatGeobuf file
+pub fn flatgeobuf(name: &str, geo_json: &GeoJson) -> Result<Vec<u8>> {
+    // Rides mix lines with their start and end points
+    let mut fgb = FgbWriter::create(name, GeometryType::Unknown)?;
+    let json = geo_json.to_string();
+    geozero::geojson::GeoJson(&json).process(&mut fgb)?;
+    let mut buffer = Vec::new();
+    fgb.write(&mut buffer)?;
+    Ok(buffer)
+}
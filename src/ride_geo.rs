@@ -1,6 +1,9 @@
-use geo::{BoundingRect, VincentyDistance};
+use geo::{BoundingRect, HaversineDistance, Simplify, SimplifyVw, VincentyDistance};
 use geo_types::{CoordFloat, CoordNum, LineString, MultiLineString, MultiPoint, Point};
-use geojson::{Feature, FeatureCollection, GeoJson, Geometry};
+use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Position};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::types::dto::geom::SimplifyAlgorithm;
 
 //Get the bounding box for a geometry as a vector
 pub trait BoundingBox<N> {
@@ -188,13 +191,13 @@ impl EndPoint for geojson::Value {
             geojson::Value::LineString(_) => geo_types::LineString::try_from(self)
                 .unwrap()
                 .points()
-                .last(),
+                .next_back(),
             geojson::Value::MultiLineString(_) => geo_types::MultiLineString::try_from(self)
                 .unwrap()
                 .iter()
                 .last()?
                 .points()
-                .last(),
+                .next_back(),
             geojson::Value::Polygon(_) => None,
             geojson::Value::MultiPolygon(_) => None,
             geojson::Value::GeometryCollection(geoms) => geoms.iter().last()?.end_point(),
@@ -236,8 +239,8 @@ pub trait Points {
 }
 
 impl Points for geojson::Value {
-    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_ + Send> {
-        match self {
+    fn points(&self) -> impl Iterator<Item = Point> + Send {
+        let points: Box<dyn Iterator<Item = Point> + '_ + Send> = match self {
             geojson::Value::Point(_) => {
                 Box::new(std::iter::once(geo_types::Point::try_from(self).unwrap()))
             }
@@ -259,9 +262,10 @@ impl Points for geojson::Value {
             geojson::Value::Polygon(_) => Box::new(std::iter::empty()),
             geojson::Value::MultiPolygon(_) => Box::new(std::iter::empty()),
             geojson::Value::GeometryCollection(geoms) => {
-                Box::new(geoms.into_iter().flat_map(|g| g.points()))
+                Box::new(geoms.iter().flat_map(|g| g.points()))
             }
-        }
+        };
+        points
     }
 }
 
@@ -272,11 +276,12 @@ impl Points for Geometry {
 }
 
 impl Points for Feature {
-    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_ + Send> {
-        match &self.geometry {
+    fn points(&self) -> impl Iterator<Item = Point> + Send {
+        let points: Box<dyn Iterator<Item = Point> + '_ + Send> = match &self.geometry {
             None => Box::new(std::iter::empty()),
             Some(geo) => Box::new(geo.points()),
-        }
+        };
+        points
     }
 }
 
@@ -287,11 +292,517 @@ impl Points for FeatureCollection {
 }
 
 impl Points for GeoJson {
-    fn points(&self) -> Box<dyn Iterator<Item = Point> + '_ + Send> {
-        match self {
+    fn points(&self) -> impl Iterator<Item = Point> + Send {
+        let points: Box<dyn Iterator<Item = Point> + '_ + Send> = match self {
             GeoJson::Geometry(geom) => Box::new(geom.points()),
             GeoJson::Feature(feat) => Box::new(feat.points()),
             GeoJson::FeatureCollection(fc) => Box::new(fc.points()),
+        };
+        points
+    }
+}
+
+/// Points along the ride's track lines, paired with their elevation if the source recorded one.
+/// Unlike Points, lone Point geometries (such as the start and end markers) are skipped.
+pub trait TrackPositions {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)>;
+}
+
+fn position_point(position: &Position) -> (Point, Option<f64>) {
+    (
+        Point::new(position[0], position[1]),
+        position.get(2).copied(),
+    )
+}
+
+impl TrackPositions for geojson::Value {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)> {
+        match self {
+            geojson::Value::MultiPoint(points) => points.iter().map(position_point).collect(),
+            geojson::Value::LineString(line) => line.iter().map(position_point).collect(),
+            geojson::Value::MultiLineString(lines) => {
+                lines.iter().flatten().map(position_point).collect()
+            }
+            geojson::Value::GeometryCollection(geoms) => {
+                geoms.iter().flat_map(|g| g.track_positions()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl TrackPositions for Geometry {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)> {
+        self.value.track_positions()
+    }
+}
+
+impl TrackPositions for Feature {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)> {
+        self.geometry
+            .as_ref()
+            .map_or(Vec::new(), |geo| geo.track_positions())
+    }
+}
+
+impl TrackPositions for FeatureCollection {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)> {
+        self.into_iter().flat_map(|f| f.track_positions()).collect()
+    }
+}
+
+impl TrackPositions for GeoJson {
+    fn track_positions(&self) -> Vec<(Point, Option<f64>)> {
+        match self {
+            GeoJson::Geometry(geom) => geom.track_positions(),
+            GeoJson::Feature(feat) => feat.track_positions(),
+            GeoJson::FeatureCollection(fc) => fc.track_positions(),
+        }
+    }
+}
+
+/// Overwrite the elevation of each track position, in the same order as TrackPositions.
+/// Positions given None are left two dimensional.
+pub trait SetTrackElevations {
+    fn set_track_elevations(&mut self, elevations: &mut impl Iterator<Item = Option<f64>>);
+}
+
+fn set_position_elevation(position: &mut Position, elevation: Option<f64>) {
+    position.truncate(2);
+    if let Some(elevation) = elevation {
+        position.push(elevation);
+    }
+}
+
+impl SetTrackElevations for geojson::Value {
+    fn set_track_elevations(&mut self, elevations: &mut impl Iterator<Item = Option<f64>>) {
+        match self {
+            geojson::Value::MultiPoint(points) => points
+                .iter_mut()
+                .zip(elevations)
+                .for_each(|(p, e)| set_position_elevation(p, e)),
+            geojson::Value::LineString(line) => line
+                .iter_mut()
+                .zip(elevations)
+                .for_each(|(p, e)| set_position_elevation(p, e)),
+            geojson::Value::MultiLineString(lines) => lines
+                .iter_mut()
+                .flatten()
+                .zip(elevations)
+                .for_each(|(p, e)| set_position_elevation(p, e)),
+            geojson::Value::GeometryCollection(geoms) => geoms
+                .iter_mut()
+                .for_each(|g| g.set_track_elevations(elevations)),
+            _ => (),
+        }
+    }
+}
+
+impl SetTrackElevations for Geometry {
+    fn set_track_elevations(&mut self, elevations: &mut impl Iterator<Item = Option<f64>>) {
+        self.value.set_track_elevations(elevations)
+    }
+}
+
+impl SetTrackElevations for Feature {
+    fn set_track_elevations(&mut self, elevations: &mut impl Iterator<Item = Option<f64>>) {
+        if let Some(geo) = self.geometry.as_mut() {
+            geo.set_track_elevations(elevations)
+        }
+    }
+}
+
+impl SetTrackElevations for FeatureCollection {
+    fn set_track_elevations(&mut self, elevations: &mut impl Iterator<Item = Option<f64>>) {
+        self.features
+            .iter_mut()
+            .for_each(|f| f.set_track_elevations(elevations))
+    }
+}
+
+/// The ride's track lines, skipping lone Point geometries such as the start and end markers
+pub trait TrackLines {
+    fn track_lines(&self) -> Vec<LineString>;
+}
+
+impl TrackLines for geojson::Value {
+    fn track_lines(&self) -> Vec<LineString> {
+        match self {
+            geojson::Value::MultiPoint(_) => {
+                vec![geo_types::MultiPoint::try_from(self).unwrap().0.into()]
+            }
+            geojson::Value::LineString(_) => vec![geo_types::LineString::try_from(self).unwrap()],
+            geojson::Value::MultiLineString(_) => {
+                geo_types::MultiLineString::try_from(self).unwrap().0
+            }
+            geojson::Value::GeometryCollection(geoms) => {
+                geoms.iter().flat_map(|g| g.track_lines()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl TrackLines for Geometry {
+    fn track_lines(&self) -> Vec<LineString> {
+        self.value.track_lines()
+    }
+}
+
+impl TrackLines for Feature {
+    fn track_lines(&self) -> Vec<LineString> {
+        self.geometry
+            .as_ref()
+            .map_or(Vec::new(), |geo| geo.track_lines())
+    }
+}
+
+impl TrackLines for FeatureCollection {
+    fn track_lines(&self) -> Vec<LineString> {
+        self.into_iter().flat_map(|f| f.track_lines()).collect()
+    }
+}
+
+impl TrackLines for GeoJson {
+    fn track_lines(&self) -> Vec<LineString> {
+        match self {
+            GeoJson::Geometry(geom) => geom.track_lines(),
+            GeoJson::Feature(feat) => feat.track_lines(),
+            GeoJson::FeatureCollection(fc) => fc.track_lines(),
+        }
+    }
+}
+
+/// Ground distance in metres covered by a pixel of a 256px web mercator tile at zoom 0, at the equator
+const ZOOM_0_METRES_PER_PIXEL: f64 = 156_543.03;
+/// Rough conversion from metres to degrees, good enough for choosing a simplification tolerance
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
+/// Simplification tolerance in metres that removes detail smaller than a pixel at the zoom level
+pub fn zoom_tolerance(zoom: f64) -> f64 {
+    ZOOM_0_METRES_PER_PIXEL / 2f64.powf(zoom)
+}
+
+/// Simplify the ride's lines, removing detail smaller than tolerance_m. Points are left as they are.
+/// Elevation isn't kept for simplified lines.
+pub trait SimplifyLines: Sized {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self;
+}
+
+fn simplify_line_string(
+    line: &LineString,
+    tolerance_m: f64,
+    algorithm: SimplifyAlgorithm,
+) -> LineString {
+    let epsilon = tolerance_m / METRES_PER_DEGREE;
+    match algorithm {
+        SimplifyAlgorithm::DouglasPeucker => line.simplify(&epsilon),
+        // Visvalingam's tolerance is an area
+        SimplifyAlgorithm::Visvalingam => line.simplify_vw(&(epsilon * epsilon)),
+    }
+}
+
+impl SimplifyLines for geojson::Value {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self {
+        match self {
+            geojson::Value::LineString(_) => {
+                let line = geo_types::LineString::try_from(self).unwrap();
+                (&simplify_line_string(&line, tolerance_m, algorithm)).into()
+            }
+            geojson::Value::MultiLineString(_) => {
+                let lines = geo_types::MultiLineString::try_from(self).unwrap();
+                (&lines
+                    .iter()
+                    .map(|line| simplify_line_string(line, tolerance_m, algorithm))
+                    .collect::<MultiLineString>())
+                    .into()
+            }
+            geojson::Value::GeometryCollection(geoms) => geojson::Value::GeometryCollection(
+                geoms
+                    .iter()
+                    .map(|g| g.simplify_lines(tolerance_m, algorithm))
+                    .collect(),
+            ),
+            value => value.clone(),
+        }
+    }
+}
+
+impl SimplifyLines for Geometry {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self {
+        Geometry {
+            value: self.value.simplify_lines(tolerance_m, algorithm),
+            ..self.clone()
+        }
+    }
+}
+
+impl SimplifyLines for Feature {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self {
+        Feature {
+            geometry: self
+                .geometry
+                .as_ref()
+                .map(|geo| geo.simplify_lines(tolerance_m, algorithm)),
+            ..self.clone()
+        }
+    }
+}
+
+impl SimplifyLines for FeatureCollection {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self {
+        FeatureCollection {
+            features: self
+                .features
+                .iter()
+                .map(|f| f.simplify_lines(tolerance_m, algorithm))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+impl SimplifyLines for GeoJson {
+    fn simplify_lines(&self, tolerance_m: f64, algorithm: SimplifyAlgorithm) -> Self {
+        match self {
+            GeoJson::Geometry(geom) => {
+                GeoJson::Geometry(geom.simplify_lines(tolerance_m, algorithm))
+            }
+            GeoJson::Feature(feat) => GeoJson::Feature(feat.simplify_lines(tolerance_m, algorithm)),
+            GeoJson::FeatureCollection(fc) => {
+                GeoJson::FeatureCollection(fc.simplify_lines(tolerance_m, algorithm))
+            }
+        }
+    }
+}
+
+/// Simplify with a growing tolerance until the lines have at most max_points points.
+/// Gives up once the tolerance is larger than any ride, where lines are down to their end points.
+pub fn simplify_to_budget<T>(geo: &T, max_points: usize, algorithm: SimplifyAlgorithm) -> T
+where
+    T: SimplifyLines + TrackLines + Clone,
+{
+    let num_points = |geo: &T| -> usize { geo.track_lines().iter().map(|l| l.0.len()).sum() };
+    if num_points(geo) <= max_points {
+        return geo.clone();
+    }
+    let mut tolerance_m = 1.0;
+    loop {
+        let simplified = geo.simplify_lines(tolerance_m, algorithm);
+        if num_points(&simplified) <= max_points || tolerance_m > 1_000_000.0 {
+            return simplified;
+        }
+        tolerance_m *= 2.0;
+    }
+}
+
+/// A position on a ride's track, found by distance along it or by snapping a point to it
+#[derive(Clone, Debug)]
+pub struct LinearPosition {
+    pub point: Point,
+    //Metres along the ride
+    pub distance: f64,
+    pub elevation: Option<f64>,
+    //Index of the track position starting the segment this position is on
+    pub seq: usize,
+}
+
+/// Cumulative distance along a ride's track positions, for linear referencing.
+/// Indices match TrackPositions, and so the seq of points from Points.
+pub struct DistanceIndex {
+    positions: Vec<(Point, Option<f64>)>,
+    distances: Vec<f64>,
+}
+
+/// Metres per degree of latitude, for projecting points onto short segments
+const PROJECTION_METRES_PER_DEGREE: f64 = 111_320.0;
+
+impl DistanceIndex {
+    pub fn new(positions: Vec<(Point, Option<f64>)>) -> Self {
+        let mut distance = 0.0;
+        let distances = positions
+            .iter()
+            .enumerate()
+            .map(|(i, (point, _))| {
+                if i > 0 {
+                    distance += positions[i - 1].0.vincenty_distance(point).unwrap_or(0.0);
+                }
+                distance
+            })
+            .collect();
+        DistanceIndex {
+            positions,
+            distances,
+        }
+    }
+
+    pub fn positions(&self) -> &[(Point, Option<f64>)] {
+        &self.positions
+    }
+
+    /// Cumulative distance in metres to each position
+    pub fn distances(&self) -> &[f64] {
+        &self.distances
+    }
+
+    pub fn total_distance(&self) -> f64 {
+        self.distances.last().copied().unwrap_or(0.0)
+    }
+
+    /// Interpolate the position a fraction of the way along the segment starting at seq
+    fn interpolate(&self, seq: usize, fraction: f64) -> LinearPosition {
+        let (start, start_elevation) = self.positions[seq];
+        let Some((end, end_elevation)) = self.positions.get(seq + 1).copied() else {
+            return LinearPosition {
+                point: start,
+                distance: self.distances[seq],
+                elevation: start_elevation,
+                seq,
+            };
+        };
+        let length = self.distances[seq + 1] - self.distances[seq];
+        LinearPosition {
+            point: Point::new(
+                start.x() + (end.x() - start.x()) * fraction,
+                start.y() + (end.y() - start.y()) * fraction,
+            ),
+            distance: self.distances[seq] + length * fraction,
+            elevation: start_elevation
+                .zip(end_elevation)
+                .map(|(s, e)| s + (e - s) * fraction)
+                .or(start_elevation),
+            seq,
+        }
+    }
+
+    /// The position distance metres along the ride, or None if that's past its end
+    pub fn position_at(&self, distance: f64) -> Option<LinearPosition> {
+        if self.positions.is_empty() || distance < 0.0 || distance > self.total_distance() {
+            return None;
+        }
+        let seq = self
+            .distances
+            .partition_point(|d| *d <= distance)
+            .saturating_sub(1);
+        let length = self
+            .distances
+            .get(seq + 1)
+            .map_or(0.0, |next| next - self.distances[seq]);
+        let fraction = if length > 0.0 {
+            (distance - self.distances[seq]) / length
+        } else {
+            0.0
+        };
+        Some(self.interpolate(seq, fraction))
+    }
+
+    /// Snap a point to the nearest position on the ride, returning it and the point's offset from it in metres
+    pub fn locate(&self, point: &Point) -> Option<(LinearPosition, f64)> {
+        // Project onto a local plane around the point, which is accurate enough over a segment
+        let scale_x = point.y().to_radians().cos() * PROJECTION_METRES_PER_DEGREE;
+        let scale_y = PROJECTION_METRES_PER_DEGREE;
+        let project = |p: &Point| ((p.x() - point.x()) * scale_x, (p.y() - point.y()) * scale_y);
+        let nearest = if self.positions.len() == 1 {
+            Some((0, 0.0))
+        } else {
+            self.positions
+                .windows(2)
+                .enumerate()
+                .map(|(seq, segment)| {
+                    let (ax, ay) = project(&segment[0].0);
+                    let (bx, by) = project(&segment[1].0);
+                    let (dx, dy) = (bx - ax, by - ay);
+                    let length_squared = dx * dx + dy * dy;
+                    let fraction = if length_squared > 0.0 {
+                        (-(ax * dx + ay * dy) / length_squared).clamp(0.0, 1.0)
+                    } else {
+                        0.0
+                    };
+                    let (px, py) = (ax + dx * fraction, ay + dy * fraction);
+                    (seq, fraction, px * px + py * py)
+                })
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+                .map(|(seq, fraction, _)| (seq, fraction))
+        };
+        let (seq, fraction) = nearest?;
+        let position = self.interpolate(seq, fraction);
+        let offset = point.haversine_distance(&position.point);
+        Some((position, offset))
+    }
+}
+
+/// Time each track position was recorded, in the same order as TrackPositions.
+/// Read from the togeojson style coordTimes property, so None for routes and older rides.
+pub trait TrackTimes {
+    fn track_times(&self) -> Vec<Option<OffsetDateTime>>;
+}
+
+fn flatten_times(value: &serde_json::Value, times: &mut Vec<Option<OffsetDateTime>>) {
+    match value {
+        serde_json::Value::Array(values) => values.iter().for_each(|v| flatten_times(v, times)),
+        serde_json::Value::String(time) => times.push(OffsetDateTime::parse(time, &Rfc3339).ok()),
+        _ => times.push(None),
+    }
+}
+
+impl TrackTimes for Feature {
+    fn track_times(&self) -> Vec<Option<OffsetDateTime>> {
+        let num_positions = self.track_positions().len();
+        let mut times = Vec::with_capacity(num_positions);
+        if let Some(coord_times) = self.property("coordTimes") {
+            flatten_times(coord_times, &mut times);
+        }
+        if times.len() != num_positions {
+            return vec![None; num_positions];
+        }
+        times
+    }
+}
+
+impl TrackTimes for FeatureCollection {
+    fn track_times(&self) -> Vec<Option<OffsetDateTime>> {
+        self.into_iter().flat_map(|f| f.track_times()).collect()
+    }
+}
+
+impl TrackTimes for GeoJson {
+    fn track_times(&self) -> Vec<Option<OffsetDateTime>> {
+        match self {
+            GeoJson::Geometry(geom) => vec![None; geom.track_positions().len()],
+            GeoJson::Feature(feat) => feat.track_times(),
+            GeoJson::FeatureCollection(fc) => fc.track_times(),
+        }
+    }
+}
+
+/// Drop the times of each track position, which are only for building profiles
+/// and no longer line up with the positions once a track is simplified
+pub trait RemoveTrackTimes {
+    fn remove_track_times(&mut self);
+}
+
+impl RemoveTrackTimes for Feature {
+    fn remove_track_times(&mut self) {
+        if let Some(properties) = self.properties.as_mut() {
+            properties.remove("coordTimes");
+        }
+    }
+}
+
+impl RemoveTrackTimes for FeatureCollection {
+    fn remove_track_times(&mut self) {
+        self.features
+            .iter_mut()
+            .for_each(|f| f.remove_track_times())
+    }
+}
+
+impl RemoveTrackTimes for GeoJson {
+    fn remove_track_times(&mut self) {
+        match self {
+            GeoJson::Geometry(_) => {}
+            GeoJson::Feature(feat) => feat.remove_track_times(),
+            GeoJson::FeatureCollection(fc) => fc.remove_track_times(),
         }
     }
 }
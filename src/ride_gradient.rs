@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    ride_geo::DistanceIndex,
+    types::dto::gradient::{GradientAnalysis, GradientBucket, GradientQuery, SteepSection},
+};
+
+/// A track point that has an elevation, with its position in the ride's points and distance along the ride
+struct ElevationPoint {
+    seq: usize,
+    distance: f64,
+    elevation: f64,
+}
+
+/// Analyse the gradient of a ride over a sliding distance window.
+/// surfaces is the aggregated surface keyed by point seq.
+/// Returns None if the ride doesn't have enough elevation data to analyse.
+pub fn gradient_analysis(
+    index: &DistanceIndex,
+    surfaces: &HashMap<usize, String>,
+    query: &GradientQuery,
+) -> Option<GradientAnalysis> {
+    let points: Vec<ElevationPoint> = index
+        .positions()
+        .iter()
+        .zip(index.distances())
+        .enumerate()
+        .filter_map(|(seq, ((_, elevation), distance))| {
+            elevation.map(|elevation| ElevationPoint {
+                seq,
+                distance: *distance,
+                elevation,
+            })
+        })
+        .collect();
+    if points.len() < 2 {
+        return None;
+    }
+
+    //Carry the last known surface forward, as only points on named ways have one
+    let mut surface: Option<&String> = None;
+    let point_surfaces: Vec<Option<&String>> = points
+        .iter()
+        .map(|p| {
+            surface = surfaces.get(&p.seq).or(surface);
+            surface
+        })
+        .collect();
+
+    //Gradient of each point, measured to the first point at least a window ahead
+    let mut gradients: Vec<(usize, usize, f64)> = Vec::new();
+    let mut j = 0;
+    for i in 0..points.len() {
+        j = j.max(i + 1);
+        while j < points.len() && points[j].distance - points[i].distance < query.gradient_window {
+            j += 1;
+        }
+        let Some(ahead) = points.get(j) else {
+            break;
+        };
+        let run = ahead.distance - points[i].distance;
+        gradients.push((i, j, (ahead.elevation - points[i].elevation) / run * 100.0));
+    }
+
+    let mut histogram = BTreeMap::<i64, f64>::new();
+    let mut add_to_histogram = |gradient: f64, distance: f64| {
+        let bucket = (gradient / query.histogram_bucket).floor() as i64;
+        *histogram.entry(bucket).or_insert(0.0) += distance;
+    };
+    for (i, _, gradient) in gradients.iter() {
+        add_to_histogram(*gradient, points[i + 1].distance - points[*i].distance);
+    }
+    // The tail is less than a window from the end, so it's measured over the partial window left
+    let tail = &points[gradients.len()..];
+    if let [start, .., end] = tail {
+        let run = end.distance - start.distance;
+        if run > 0.0 {
+            add_to_histogram((end.elevation - start.elevation) / run * 100.0, run);
+        }
+    }
+
+    let threshold = |surface: Option<&String>| match surface.map(|s| s.as_str()) {
+        Some("dirt") => query.steep_dirt_threshold,
+        _ => query.steep_threshold,
+    };
+    let mut steep_sections = Vec::<SteepSection>::new();
+    let mut in_section = false;
+    for (i, j, gradient) in gradients.iter() {
+        let surface = point_surfaces[*i];
+        if gradient.abs() < threshold(surface) {
+            in_section = false;
+            continue;
+        }
+        match steep_sections.last_mut() {
+            Some(section) if in_section => {
+                section.end_distance = points[*j].distance;
+                if gradient.abs() > section.max_gradient.abs() {
+                    section.max_gradient = *gradient;
+                    section.surface = surface.cloned();
+                }
+            }
+            _ => steep_sections.push(SteepSection {
+                start_distance: points[*i].distance,
+                end_distance: points[*j].distance,
+                max_gradient: *gradient,
+                surface: surface.cloned(),
+            }),
+        }
+        in_section = true;
+    }
+
+    Some(GradientAnalysis {
+        steep_sections,
+        histogram: histogram
+            .into_iter()
+            .map(|(bucket, distance)| GradientBucket {
+                min_gradient: bucket as f64 * query.histogram_bucket,
+                max_gradient: (bucket + 1) as f64 * query.histogram_bucket,
+                distance,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use geo_types::Point;
+
+    use super::*;
+
+    #[test]
+    fn histogram_covers_the_whole_ride() {
+        // About 111m per thousandth of a degree, climbing then levelling off
+        let index = DistanceIndex::new(
+            [0.0, 5.0, 10.0, 12.0, 12.0, 12.5]
+                .into_iter()
+                .enumerate()
+                .map(|(i, elevation)| (Point::new(i as f64 * 0.001, 0.0), Some(elevation)))
+                .collect(),
+        );
+        let query = GradientQuery {
+            gradient_window: 250.0,
+            ..GradientQuery::default()
+        };
+        let analysis = gradient_analysis(&index, &HashMap::new(), &query).unwrap();
+
+        let distance: f64 = analysis.histogram.iter().map(|b| b.distance).sum();
+        assert!((distance - index.total_distance()).abs() < 1e-6);
+    }
+}
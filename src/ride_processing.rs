@@ -1,103 +1,285 @@
-use std::{collections::HashMap, sync::Arc};
-
 use bigdecimal::BigDecimal;
 use color_eyre::eyre::{eyre, Result};
-use futures::stream::{self, TryStreamExt};
-use geo::VincentyDistance;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use geo_types::Point;
-use google_maps::{prelude::*, LatLng};
-use tokio::{sync::Mutex, try_join};
-use tracing::{debug, info, instrument};
+use num_traits::ToPrimitive;
+use tokio::join;
+use tracing::{debug, instrument, warn};
 
 use crate::{
-    clients::{get_google_maps, get_nominatim_url, get_reqwest_client},
+    clients::App,
+    drive_times::DriveTimes,
+    geocoder::{AnyGeocoder, Geocoder},
+    ride_geo::{DistanceIndex, LinearPosition},
     types::{
-        dto::nominatim::{NominatimDetailsPlace, NominatimPlace},
+        dto::{
+            nominatim::{Address, NominatimDetailsPlace, NominatimPlace},
+            ride::{Reconciliation, RoadSummary},
+        },
         model::{
             self,
-            ride::{ProcessedRide, RideWay, WayPoint},
+            ride::{ProcessedRide, RideWay, UnmatchedStretch, WayBreakdown, WayPoint},
         },
     },
 };
 
-pub async fn nominatim_reverse_geocode(point: &Point) -> Result<NominatimPlace> {
-    let url = format!(
-        "{base_url}/reverse?lat={lat}&lon={lon}&extratags=1&format=jsonv2",
-        base_url = get_nominatim_url()?,
-        lat = point.y(),
-        lon = point.x()
-    );
-    let place = get_reqwest_client()?
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<NominatimPlace>()
-        .await?;
-    Ok(place)
-}
-
-pub async fn nominatim_get_place(osm_type: &str, osm_id: u64) -> Result<NominatimDetailsPlace> {
-    let url = format!(
-        "{base_url}/details?osmtype={osm_type}&osmid={osm_id}&addressdetails=1&format=json",
-        base_url = get_nominatim_url()?
-    );
-    let place = get_reqwest_client()?
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<NominatimDetailsPlace>()
-        .await?;
-    Ok(place)
+/// The road under a point, from the local OSM extract if one is loaded, otherwise from the geocoder.
+/// None if the point isn't on a road.
+pub async fn way_reverse_geocode(app: &App, point: &Point) -> Result<Option<NominatimPlace>> {
+    if let Some(index) = &app.osm_index {
+        return Ok(index.reverse_geocode(point));
+    }
+    let place = app.geocoder.reverse(point, None).await?;
+    let is_road = place.osm_type == "way" && place.category == Some(String::from("highway"));
+    Ok(is_road.then_some(place))
+}
+
+/// A way's details, from the local OSM extract if one is loaded, otherwise from the geocoder
+pub async fn way_details(app: &App, osm_id: u64) -> Result<NominatimDetailsPlace> {
+    match &app.osm_index {
+        Some(index) => index
+            .way(osm_id)
+            .ok_or(eyre!("No way {osm_id} in OSM extract")),
+        None => app.geocoder.details("W", osm_id).await,
+    }
+}
+
+/// Samples closer together than this aren't bisected any further when the road changes between them
+const WAY_CHANGE_PRECISION: f64 = 10.0;
+
+/// What a looked up position is on, consecutive samples on the same thing form a segment
+#[derive(PartialEq)]
+enum SegmentKey {
+    //Roads are matched by name, not id, as the same road can have multiple place ids
+    Named(String),
+    Unnamed(u64),
+    Unmatched,
 }
 
-#[instrument(skip(route))]
+/// A position along the route and the road it's on, if any
+struct Sample {
+    //Metres along the route
+    distance: f64,
+    place: Option<NominatimPlace>,
+}
+
+impl Sample {
+    fn key(&self) -> SegmentKey {
+        match &self.place {
+            Some(NominatimPlace {
+                name: Some(name), ..
+            }) => SegmentKey::Named(name.clone()),
+            Some(place) => SegmentKey::Unnamed(place.osm_id),
+            None => SegmentKey::Unmatched,
+        }
+    }
+}
+
+/// Consecutive samples on the same road, or on no road
+struct Run {
+    key: SegmentKey,
+    samples: Vec<Sample>,
+}
+
+/// In parallel, look up the road at each distance along the route, keeping them in order
+async fn sample_route(
+    app: &App,
+    route: &DistanceIndex,
+    distances: Vec<f64>,
+) -> Result<Vec<Sample>> {
+    stream::iter(distances)
+        .map(|distance| async move {
+            let position = route
+                .position_at(distance)
+                .ok_or(eyre!("No position {distance}m along route"))?;
+            let place = way_reverse_geocode(app, &position.point).await?;
+            Ok::<_, color_eyre::eyre::Error>(Sample { distance, place })
+        })
+        .buffered(50)
+        .try_collect()
+        .await
+}
+
+/// Break the route into an ordered sequence of contiguous segments, one for each stretch on the same road,
+/// and the stretches which aren't on any road.
+/// The route is sampled at an even spacing rather than at its recorded points, which may be far denser or sparser,
+/// then bisected wherever the road changes between samples to find where.
+#[instrument(skip(app, route))]
 pub async fn ride_ways(
-    route: impl Iterator<Item = Point> + Send,
+    app: &App,
+    route: &DistanceIndex,
+    total_distance: &BigDecimal,
+) -> Result<WayBreakdown> {
+    if route.positions().is_empty() {
+        return Ok(WayBreakdown {
+            ways: Vec::new(),
+            unmatched: Vec::new(),
+        });
+    }
+    let spacing = app.config.features.way_sample_spacing;
+    let route_distance = route.total_distance();
+    let intervals = (route_distance / spacing).ceil() as usize;
+    let distances: Vec<f64> = (0..=intervals)
+        .map(|i| (i as f64 * spacing).min(route_distance))
+        .collect();
+    let mut samples = sample_route(app, route, distances).await?;
+    loop {
+        let midpoints: Vec<f64> = samples
+            .windows(2)
+            .filter(|pair| {
+                pair[1].distance - pair[0].distance > WAY_CHANGE_PRECISION
+                    && pair[0].key() != pair[1].key()
+            })
+            .map(|pair| (pair[0].distance + pair[1].distance) / 2.0)
+            .collect();
+        if midpoints.is_empty() {
+            break;
+        }
+        debug!("Bisecting {} road changes", midpoints.len());
+        samples.extend(sample_route(app, route, midpoints).await?);
+        samples.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    }
+    debug!(
+        "Sampled {} positions for {} track points",
+        samples.len(),
+        route.positions().len()
+    );
+    let breakdown = segments(route, samples);
+    let reconciliation = reconcile(&breakdown.ways, &breakdown.unmatched, total_distance);
+    debug!(?reconciliation, "Reconciled ways against total distance");
+    Ok(breakdown)
+}
+
+/// Group the samples, in order along the route, into segments on the same road or on none
+fn segments(route: &DistanceIndex, samples: Vec<Sample>) -> WayBreakdown {
+    let mut breakdown = WayBreakdown {
+        ways: Vec::new(),
+        unmatched: Vec::new(),
+    };
+    let route_distance = route.total_distance();
+    let mut runs: Vec<Run> = Vec::new();
+    for sample in samples {
+        let key = sample.key();
+        match runs.last_mut() {
+            Some(run) if run.key == key => run.samples.push(sample),
+            _ => runs.push(Run {
+                key,
+                samples: vec![sample],
+            }),
+        }
+    }
+
+    //Roads change somewhere between one run's last sample and the next run's first, split the difference
+    let boundaries: Vec<f64> = runs
+        .windows(2)
+        .map(|pair| {
+            let last = pair[0].samples.last().map_or(0.0, |s| s.distance);
+            let first = pair[1].samples.first().map_or(0.0, |s| s.distance);
+            (last + first) / 2.0
+        })
+        .collect();
+    let run_count = runs.len();
+    for (i, run) in runs.into_iter().enumerate() {
+        let start_distance = if i == 0 { 0.0 } else { boundaries[i - 1] };
+        let end_distance = boundaries.get(i).copied().unwrap_or(route_distance);
+        //Every track point within the segment, the last segment keeping the route's final point
+        let first_seq = route.distances().partition_point(|d| *d < start_distance);
+        let end_seq = if i + 1 == run_count {
+            route.positions().len()
+        } else {
+            route.distances().partition_point(|d| *d < end_distance)
+        };
+        let points: Vec<WayPoint> = (first_seq..end_seq)
+            .map(|seq| WayPoint {
+                seq,
+                point: route.positions()[seq].0,
+            })
+            .collect();
+        //Numbered by their order along the route, as several may fall between two track points
+        let seq: u64 = i.try_into().expect("Couldn't convert usize to u64");
+        let mut osm_ids: Vec<u64> = Vec::new();
+        let mut first_place: Option<NominatimPlace> = None;
+        for place in run.samples.into_iter().filter_map(|sample| sample.place) {
+            if !osm_ids.contains(&place.osm_id) {
+                osm_ids.push(place.osm_id);
+            }
+            first_place.get_or_insert(place);
+        }
+        match first_place {
+            Some(place) => breakdown.ways.push(RideWay {
+                seq,
+                osm_id: place.osm_id,
+                osm_ids,
+                name: place.name,
+                highway: place.place_type,
+                region: place.address.and_then(|address| address.state),
+                surface: place
+                    .extratags
+                    .surface
+                    .as_deref()
+                    .map(|s| aggregate_surface(s).to_string()),
+                start_distance,
+                end_distance,
+                distance: end_distance - start_distance,
+                points,
+            }),
+            None => breakdown.unmatched.push(UnmatchedStretch {
+                seq,
+                start_distance,
+                end_distance,
+                distance: end_distance - start_distance,
+            }),
+        }
+    }
+    breakdown
+}
+
+/// Compare the distance of a ride's segments against its total distance.
+/// The two can differ a little as the total skips gaps between separately recorded tracks.
+pub fn reconcile(
+    ways: &[RideWay],
+    unmatched: &[UnmatchedStretch],
     total_distance: &BigDecimal,
-) -> Result<Vec<RideWay>> {
-    //In parallel, get places from nominatim corresponding to coordinates, group them by place id
-    let ways = Arc::new(Mutex::new(HashMap::<String, RideWay>::new()));
-    stream::iter(route.enumerate().map(Ok))
-        .try_for_each_concurrent(50, |(seq, point)| {
-            let ways = ways.clone();
-            async move {
-                let place = nominatim_reverse_geocode(&point).await?;
-                if place.osm_type == "way" && place.category == Some(String::from("highway")) {
-                    if let Some(place_name) = place.name {
-                        let mut ways = ways.lock().await;
-                        //We use name, not id, as the hash to group by, as the same road can have multiple place ids
-                        let way = ways.entry(place_name).or_insert(RideWay {
-                            seq: seq.try_into().expect("Couldn't convert usize to u64"),
-                            osm_id: place.osm_id,
-                            distance: 0.0,
-                            points: Vec::new(),
-                        });
-                        way.points.push(WayPoint { seq, point })
+) -> Reconciliation {
+    let total_distance = total_distance.to_f64().unwrap_or(0.0);
+    let matched_distance: f64 = ways.iter().map(|way| way.distance).sum();
+    let unmatched_distance: f64 = unmatched.iter().map(|stretch| stretch.distance).sum();
+    Reconciliation {
+        total_distance,
+        matched_distance,
+        unmatched_distance,
+        difference: total_distance - matched_distance - unmatched_distance,
+    }
+}
+
+/// Group a ride's segments by road, in the order each road is first ridden.
+/// Segments are the same road when they share an OSM way, separate roads with the same name are kept apart.
+pub fn road_summary(ways: &[RideWay]) -> Vec<RoadSummary> {
+    let mut roads: Vec<RoadSummary> = Vec::new();
+    for way in ways {
+        let label = way.label();
+        match roads.iter_mut().find(|road| {
+            road.label == label && way.osm_ids.iter().any(|id| road.osm_ids.contains(id))
+        }) {
+            Some(road) => {
+                road.distance += way.distance;
+                road.segments += 1;
+                way.osm_ids.iter().for_each(|osm_id| {
+                    if !road.osm_ids.contains(osm_id) {
+                        road.osm_ids.push(*osm_id);
                     }
-                }
-                Ok::<(), color_eyre::eyre::Error>(())
+                });
             }
-        })
-        .await?;
-    //Dont need our mutex anymore.
-    let mut ways = Arc::into_inner(ways)
-        .expect("Couldnt unwrap arc!")
-        .into_inner();
-    //Now calculate the distance of each place.
-    ways.values_mut().for_each(|way| {
-        way.points.sort_by_key(|p| p.seq);
-        way.distance = way
-            .points
-            .iter()
-            .map_windows(|[p1, p2]| p1.point.vincenty_distance(&p2.point).unwrap_or(0.0))
-            .sum();
-    });
-    let mut ways_vec = ways.into_values().collect::<Vec<RideWay>>();
-    // ways_vec.sort_by(|way1, way2| way1.distance.total_cmp(&way2.distance).reverse());
-    ways_vec.sort_unstable_by_key(|way| way.seq);
-    Ok(ways_vec)
+            None => roads.push(RoadSummary {
+                label,
+                name: way.name.clone(),
+                osm_ids: way.osm_ids.clone(),
+                distance: way.distance,
+                segments: 1,
+            }),
+        }
+    }
+    roads
 }
 
 pub fn aggregate_surface(surface: &str) -> &str {
@@ -108,79 +290,215 @@ pub fn aggregate_surface(surface: &str) -> &str {
     }
 }
 
-#[instrument]
-pub async fn time_to_start_and_from_end(
-    origin: LatLng,
-    start: LatLng,
-    end: LatLng,
-) -> Result<(Duration, Duration)> {
-    let distances = get_google_maps()?
-        .distance_matrix(
-            vec![Waypoint::LatLng(origin.clone()), Waypoint::LatLng(end)],
-            vec![Waypoint::LatLng(start), Waypoint::LatLng(origin)],
-        )
-        .execute()
-        .await?;
-
-    let origin_to_start = distances
-        .rows
-        .first()
-        .ok_or(eyre!("No row 0!"))?
-        .elements
-        .first()
-        .ok_or(eyre!("No element 0!"))?
-        .duration
-        .clone()
-        .ok_or(eyre!("No duration 0!"))?
-        .value;
-
-    let end_to_origin = distances
-        .rows
-        .get(1)
-        .ok_or(eyre!("No row 1!"))?
-        .elements
-        .get(1)
-        .ok_or(eyre!("No element 1!"))?
-        .duration
-        .clone()
-        .ok_or(eyre!("No duration 1!"))?
-        .value;
-
-    Ok((origin_to_start, end_to_origin))
-}
-
+/// Look up a ride's start and end addresses, with names in the language if given
 pub async fn process_ride(
+    geocoder: &AnyGeocoder,
     ride: model::ride::QueryRide,
-    origin: Option<LatLng>,
+    times: DriveTimes,
+    language: Option<&str>,
 ) -> Result<ProcessedRide> {
     let start_point = ride.start_point.ok_or(eyre!("No start point"))?.0;
     let end_point = ride.end_point.ok_or(eyre!("No end point"))?.0;
-    let start_coords = LatLng::try_from(&start_point)?;
-    let end_coords = LatLng::try_from(&end_point)?;
-    let time_fut = async move {
-        if let Some(origin) = origin {
-            let res = time_to_start_and_from_end(origin, start_coords, end_coords).await?;
-            Ok(Some(res))
-        } else {
-            Ok(None)
-        }
-    };
-    let (start_address, end_address, times) = try_join!(
-        nominatim_reverse_geocode(&start_point),
-        nominatim_reverse_geocode(&end_point),
-        time_fut
-    )?;
+    let (start_place, end_place) = join!(
+        geocoder.reverse(&start_point, language),
+        geocoder.reverse(&end_point, language),
+    );
+    let mut warnings = Vec::new();
+    let start_address = address_or_warning(ride.id, "start", start_place, &mut warnings);
+    let end_address = address_or_warning(ride.id, "end", end_place, &mut warnings);
+    if times.to_start_failed {
+        warnings.push(String::from("Drive time to start unavailable"));
+    }
+    if times.from_end_failed {
+        warnings.push(String::from("Drive time from end unavailable"));
+    }
     Ok(model::ride::ProcessedRide {
         id: ride.id,
         name: ride.name,
-        start_address: start_address.address.into(),
-        end_address: end_address.address.into(),
+        start_address,
+        end_address,
         total_distance: ride.total_distance,
-        time_from_origin_to_start: times.map(|t| t.0.num_seconds()),
-        time_from_end_to_origin: times.map(|t| t.1.num_seconds()),
-        start_point,
-        end_point,
+        time_from_origin_to_start: times.to_start.map(|t| t.typical),
+        time_from_end_to_origin: times.from_end.map(|t| t.typical),
+        time_from_origin_to_start_pessimistic: times.to_start.and_then(|t| t.pessimistic),
+        time_from_end_to_origin_pessimistic: times.from_end.and_then(|t| t.pessimistic),
         geo_json: ride.geo_json,
-        ways: ride.ways,
+        preview_geo_json: ride.preview_geo_json,
+        warnings,
     })
 }
+
+/// The address of a reverse geocoded place, or None with a warning if the geocoder failed.
+/// Places far from anywhere can come back without an address, they get an empty one.
+fn address_or_warning(
+    ride_id: i64,
+    end: &str,
+    place: Result<NominatimPlace>,
+    warnings: &mut Vec<String>,
+) -> Option<Address> {
+    match place {
+        Ok(place) => Some(place.address.unwrap_or_default()),
+        Err(e) => {
+            warn!("Couldn't reverse geocode ride {ride_id}'s {end}: {e:#}");
+            warnings.push(format!("{end} address unavailable"));
+            None
+        }
+    }
+}
+
+/// The segment a position along the ride is on, None if it's on no road
+pub fn way_at<'a>(ways: &'a [RideWay], position: &LinearPosition) -> Option<&'a RideWay> {
+    //Rides imported before segments have no distances, but each way's seq is its first track point
+    if ways.iter().all(|way| way.end_distance == 0.0) {
+        return ways
+            .iter()
+            .rev()
+            .find(|way| way.seq as usize <= position.seq);
+    }
+    ways.iter()
+        .find(|way| (way.start_distance..=way.end_distance).contains(&position.distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::dto::nominatim::ExtraTags;
+
+    fn road(osm_id: u64, name: &str) -> Option<NominatimPlace> {
+        Some(NominatimPlace {
+            osm_type: String::from("way"),
+            osm_id,
+            display_name: name.to_string(),
+            category: Some(String::from("highway")),
+            place_type: Some(String::from("residential")),
+            name: Some(name.to_string()),
+            address: None,
+            extratags: ExtraTags { surface: None },
+        })
+    }
+
+    #[test]
+    fn numbers_segments_between_adjacent_track_points() {
+        let route = DistanceIndex::new(vec![
+            (Point::new(151.0, -33.0), None),
+            (Point::new(151.01, -33.0), None),
+        ]);
+        let total = route.total_distance();
+        // The road changes twice between the route's only two track points
+        let samples = vec![
+            Sample {
+                distance: 0.0,
+                place: road(1, "First Street"),
+            },
+            Sample {
+                distance: total * 0.4,
+                place: road(2, "Second Street"),
+            },
+            Sample {
+                distance: total * 0.45,
+                place: None,
+            },
+            Sample {
+                distance: total * 0.6,
+                place: road(3, "Third Street"),
+            },
+            Sample {
+                distance: total,
+                place: road(3, "Third Street"),
+            },
+        ];
+        let breakdown = segments(&route, samples);
+
+        let ways: Vec<(u64, u64)> = breakdown
+            .ways
+            .iter()
+            .map(|way| (way.seq, way.osm_id))
+            .collect();
+        assert_eq!(ways, vec![(0, 1), (1, 2), (3, 3)]);
+        let unmatched: Vec<u64> = breakdown.unmatched.iter().map(|u| u.seq).collect();
+        assert_eq!(unmatched, vec![2]);
+        // Only the segments holding a track point list it
+        assert_eq!(breakdown.ways[0].points.len(), 1);
+        assert!(breakdown.ways[1].points.is_empty());
+        assert_eq!(breakdown.ways[2].points.len(), 1);
+        assert_eq!(breakdown.ways[2].end_distance, total);
+    }
+
+    #[test]
+    fn finds_way_by_distance() {
+        let route = DistanceIndex::new(vec![
+            (Point::new(151.0, -33.0), None),
+            (Point::new(151.01, -33.0), None),
+        ]);
+        let total = route.total_distance();
+        let samples = vec![
+            Sample {
+                distance: 0.0,
+                place: road(1, "First Street"),
+            },
+            Sample {
+                distance: total * 0.5,
+                place: road(2, "Second Street"),
+            },
+            Sample {
+                distance: total,
+                place: road(2, "Second Street"),
+            },
+        ];
+        let ways = segments(&route, samples).ways;
+        let at = |fraction: f64| {
+            let position = route.position_at(total * fraction).unwrap();
+            way_at(&ways, &position).map(|way| way.osm_id)
+        };
+        assert_eq!(at(0.1), Some(1));
+        assert_eq!(at(0.9), Some(2));
+        assert_eq!(at(1.0), Some(2));
+    }
+
+    fn way(seq: u64, osm_id: u64, name: &str, distance: f64) -> RideWay {
+        RideWay {
+            seq,
+            osm_id,
+            osm_ids: vec![osm_id],
+            name: Some(name.to_string()),
+            highway: Some(String::from("secondary")),
+            region: None,
+            surface: None,
+            start_distance: 0.0,
+            end_distance: distance,
+            distance,
+            points: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_separate_roads_with_the_same_name_apart() {
+        let roads = road_summary(&[
+            way(0, 1, "Main Road", 1000.0),
+            way(1, 2, "Bridge Street", 200.0),
+            // Back on the first Main Road
+            way(2, 1, "Main Road", 500.0),
+            // A different Main Road in the next town
+            way(3, 3, "Main Road", 300.0),
+        ]);
+        let summary: Vec<(&str, &[u64], f64, usize)> = roads
+            .iter()
+            .map(|road| {
+                (
+                    road.label.as_str(),
+                    road.osm_ids.as_slice(),
+                    road.distance,
+                    road.segments,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("Main Road", &[1][..], 1500.0, 2),
+                ("Bridge Street", &[2][..], 200.0, 1),
+                ("Main Road", &[3][..], 300.0, 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use time::OffsetDateTime;
+
+use crate::{
+    ride_geo::DistanceIndex,
+    ride_processing::way_at,
+    types::{dto::profile::ProfilePoint, model::ride::RideWay},
+};
+
+/// Most samples returned in a profile, the interval is widened to stay under it
+const MAX_SAMPLES: f64 = 10_000.0;
+
+/// Interpolate the time a distance along the ride was reached
+fn time_at(index: &DistanceIndex, times: &[Option<OffsetDateTime>], distance: f64) -> Option<f64> {
+    let position = index.position_at(distance)?;
+    let distances = index.distances();
+    let start = times
+        .get(position.seq)
+        .copied()
+        .flatten()?
+        .unix_timestamp_nanos() as f64;
+    let Some(end) = times.get(position.seq + 1).copied().flatten() else {
+        return Some(start / 1e9);
+    };
+    let length = distances[position.seq + 1] - distances[position.seq];
+    let fraction = if length > 0.0 {
+        (distance - distances[position.seq]) / length
+    } else {
+        0.0
+    };
+    Some((start + (end.unix_timestamp_nanos() as f64 - start) * fraction) / 1e9)
+}
+
+/// Sample the ride every interval metres, from its start to its end
+pub fn ride_profile(
+    index: &DistanceIndex,
+    times: &[Option<OffsetDateTime>],
+    ways: &[RideWay],
+    interval: f64,
+) -> Vec<ProfilePoint> {
+    let total = index.total_distance();
+    let interval = interval.max(1.0).max(total / MAX_SAMPLES);
+    let mut distances: Vec<f64> = (0..)
+        .map(|i| i as f64 * interval)
+        .take_while(|d| *d < total)
+        .collect();
+    distances.push(total);
+    let elevation_at = |distance: f64| {
+        index
+            .position_at(distance.clamp(0.0, total))
+            .and_then(|p| p.elevation)
+    };
+    let mut previous: Option<(f64, Option<f64>)> = None;
+    distances
+        .into_iter()
+        .filter_map(|distance| {
+            let position = index.position_at(distance)?;
+            let (before, after) = (
+                (distance - interval / 2.0).max(0.0),
+                (distance + interval / 2.0).min(total),
+            );
+            let gradient = elevation_at(before)
+                .zip(elevation_at(after))
+                .filter(|_| after > before)
+                .map(|(b, a)| (a - b) / (after - before) * 100.0);
+            let time = time_at(index, times, distance);
+            let speed = previous.and_then(|(previous_distance, previous_time)| {
+                let seconds = time? - previous_time?;
+                (seconds > 0.0).then(|| (distance - previous_distance) / seconds * 3.6)
+            });
+            previous = Some((distance, time));
+            let way = way_at(ways, &position);
+            Some(ProfilePoint {
+                distance,
+                elevation: position.elevation,
+                gradient,
+                surface: way.and_then(|w| w.surface.clone()),
+                way_name: way.and_then(|w| w.name.clone()),
+                speed,
+            })
+        })
+        .collect()
+}
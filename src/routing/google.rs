@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use google_maps::{prelude::*, LatLng};
+use time::OffsetDateTime;
+use tokio::try_join;
+
+use crate::upstream::{Attempt, Upstreams};
+
+use super::{DriveDuration, DurationMatrix, RoutingProvider};
+
+/// Host the Google Maps client calls, for limiting requests to it alongside the others
+pub const GOOGLE_MAPS_HOST: &str = "maps.googleapis.com";
+
+/// Google's Distance Matrix API.
+/// The google_maps client only builds the request URLs, they're sent through Upstreams so every HTTP call is
+/// limited and retried there, rather than also by the client's own backoff.
+pub struct Google {
+    client: GoogleMapsClient,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Google {
+    pub fn new(api_key: &str, upstreams: Arc<Upstreams>) -> Self {
+        Google {
+            client: GoogleMapsClient::new(api_key),
+            upstreams,
+        }
+    }
+}
+
+fn waypoints(points: &[Point]) -> Result<Vec<Waypoint>> {
+    points
+        .iter()
+        .map(|point| Ok(Waypoint::LatLng(LatLng::try_from(point)?)))
+        .collect()
+}
+
+/// Judge a response by the status Google gives in its body.
+/// Failures and being over the per second limit are worth retrying, a request Google turned down isn't.
+fn status_attempt(response: DistanceMatrixResponse) -> Attempt<DistanceMatrixResponse> {
+    let error = || {
+        eyre!(
+            "Google responded {:?}: {}",
+            response.status,
+            response.error_message.as_deref().unwrap_or_default()
+        )
+    };
+    match response.status {
+        DistanceMatrixStatus::Ok => Attempt::Success(response),
+        DistanceMatrixStatus::OverQueryLimit | DistanceMatrixStatus::UnknownError => {
+            Attempt::Failed(error(), None)
+        }
+        _ => Attempt::Rejected(error()),
+    }
+}
+
+impl Google {
+    async fn request(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        departure: Option<NaiveDateTime>,
+        traffic_model: TrafficModel,
+    ) -> Result<Vec<Vec<Option<i64>>>> {
+        let mut request = self
+            .client
+            .distance_matrix(waypoints(sources)?, waypoints(destinations)?);
+        let url = &match departure {
+            Some(departure) => request
+                .with_departure_time(DepartureTime::At(departure))
+                .with_traffic_model(traffic_model)
+                .query_url()?,
+            None => request.query_url()?,
+        };
+        let response = self
+            .upstreams
+            .run(GOOGLE_MAPS_HOST, "Google distance matrix", || {
+                let request = self.upstreams.get(url);
+                async move {
+                    match Attempt::from_response(request.send().await) {
+                        Attempt::Success(response) => {
+                            match response.json::<DistanceMatrixResponse>().await {
+                                Ok(response) => status_attempt(response),
+                                Err(error) => Attempt::Failed(error.into(), None),
+                            }
+                        }
+                        Attempt::Rejected(error) => Attempt::Rejected(error),
+                        Attempt::Failed(error, wait) => Attempt::Failed(error, wait),
+                    }
+                }
+            })
+            .await?;
+        //Durations in traffic are only given with a departure time
+        Ok(response
+            .rows
+            .into_iter()
+            .map(|row| {
+                row.elements
+                    .into_iter()
+                    .map(|element| {
+                        element
+                            .duration_in_traffic
+                            .or(element.duration)
+                            .map(|d| d.value.num_seconds())
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+impl RoutingProvider for Google {
+    // The Distance Matrix API takes at most 25 origins or destinations
+    fn max_batch(&self) -> usize {
+        25
+    }
+
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix> {
+        let departure = departure
+            .map(|d| {
+                NaiveDateTime::from_timestamp_opt(d.unix_timestamp(), 0)
+                    .ok_or(eyre!("Invalid departure time {d}"))
+            })
+            .transpose()?;
+        //Traffic models only apply with a departure time, without one there's only the typical duration
+        let (typical, pessimistic) = match departure {
+            Some(_) => try_join!(
+                self.request(sources, destinations, departure, TrafficModel::BestGuess),
+                self.request(sources, destinations, departure, TrafficModel::Pessimistic)
+            )?,
+            None => (
+                self.request(sources, destinations, None, TrafficModel::BestGuess)
+                    .await?,
+                Vec::new(),
+            ),
+        };
+        Ok(typical
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                row.into_iter()
+                    .enumerate()
+                    .map(|(j, typical)| {
+                        Some(DriveDuration {
+                            typical: typical?,
+                            pessimistic: pessimistic
+                                .get(i)
+                                .and_then(|row| row.get(j).copied().flatten()),
+                        })
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: DistanceMatrixStatus) -> DistanceMatrixResponse {
+        DistanceMatrixResponse {
+            destination_addresses: Vec::new(),
+            error_message: None,
+            origin_addresses: Vec::new(),
+            rows: Vec::new(),
+            status,
+        }
+    }
+
+    #[test]
+    fn rejects_requests_google_turned_down() {
+        for status in [
+            DistanceMatrixStatus::InvalidRequest,
+            DistanceMatrixStatus::RequestDenied,
+            DistanceMatrixStatus::MaxElementsExceeded,
+            DistanceMatrixStatus::OverDailyLimit,
+        ] {
+            assert!(matches!(
+                status_attempt(response(status)),
+                Attempt::Rejected(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn retries_failures_and_limits() {
+        for status in [
+            DistanceMatrixStatus::OverQueryLimit,
+            DistanceMatrixStatus::UnknownError,
+        ] {
+            assert!(matches!(
+                status_attempt(response(status)),
+                Attempt::Failed(_, None)
+            ));
+        }
+        assert!(matches!(
+            status_attempt(response(DistanceMatrixStatus::Ok)),
+            Attempt::Success(_)
+        ));
+    }
+}
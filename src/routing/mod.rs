@@ -0,0 +1,132 @@
+pub mod google;
+pub mod osrm;
+pub mod straight_line;
+pub mod valhalla;
+
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::{MultiPolygon, Point};
+use time::OffsetDateTime;
+
+use crate::upstream::Upstreams;
+
+use self::{google::Google, osrm::Osrm, straight_line::StraightLine, valhalla::Valhalla};
+
+/// A drive time in seconds, and the time in heavy traffic if the provider models traffic
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DriveDuration {
+    pub typical: i64,
+    pub pessimistic: Option<i64>,
+}
+
+impl DriveDuration {
+    /// A duration from a provider without traffic
+    pub fn untrafficked(seconds: f64) -> Self {
+        DriveDuration {
+            typical: seconds.round() as i64,
+            pessimistic: None,
+        }
+    }
+}
+
+/// Drive times indexed by source then destination. None where there's no route.
+pub type DurationMatrix = Vec<Vec<Option<DriveDuration>>>;
+
+/// Finds drive times between points
+pub trait RoutingProvider {
+    /// Most points to send alongside a single other point in one matrix request
+    fn max_batch(&self) -> usize;
+    /// Drive times leaving at the departure time, or at no particular time if there isn't one
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix>;
+
+    /// The area reachable from the origin within the time, or None if the provider can't tell
+    async fn isochrone(&self, _origin: Point, _seconds: i64) -> Result<Option<MultiPolygon>> {
+        Ok(None)
+    }
+}
+
+/// The routing provider chosen by config
+pub enum AnyRoutingProvider {
+    Google(Google),
+    Osrm(Osrm),
+    Valhalla(Valhalla),
+    StraightLine(StraightLine),
+}
+
+impl AnyRoutingProvider {
+    /// Configure a provider by name, which is one of google, osrm, valhalla or straight_line.
+    /// Google takes an API key, OSRM and Valhalla the url of their server, and straight_line nothing.
+    pub fn new(kind: &str, key_or_url: String, upstreams: Arc<Upstreams>) -> Result<Self> {
+        if key_or_url.is_empty() && kind != "straight_line" {
+            return Err(eyre!("No key or url for routing provider {kind}"));
+        }
+        match kind {
+            "google" => Ok(AnyRoutingProvider::Google(Google::new(
+                &key_or_url,
+                upstreams,
+            ))),
+            "osrm" => Ok(AnyRoutingProvider::Osrm(Osrm::new(key_or_url, upstreams))),
+            "valhalla" => Ok(AnyRoutingProvider::Valhalla(Valhalla::new(
+                key_or_url, upstreams,
+            ))),
+            "straight_line" => Ok(AnyRoutingProvider::StraightLine(StraightLine::new())),
+            _ => Err(eyre!("Unknown routing provider {kind}")),
+        }
+    }
+}
+
+impl RoutingProvider for AnyRoutingProvider {
+    fn max_batch(&self) -> usize {
+        match self {
+            AnyRoutingProvider::Google(provider) => provider.max_batch(),
+            AnyRoutingProvider::Osrm(provider) => provider.max_batch(),
+            AnyRoutingProvider::Valhalla(provider) => provider.max_batch(),
+            AnyRoutingProvider::StraightLine(provider) => provider.max_batch(),
+        }
+    }
+
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix> {
+        match self {
+            AnyRoutingProvider::Google(provider) => {
+                provider
+                    .duration_matrix(sources, destinations, departure)
+                    .await
+            }
+            AnyRoutingProvider::Osrm(provider) => {
+                provider
+                    .duration_matrix(sources, destinations, departure)
+                    .await
+            }
+            AnyRoutingProvider::Valhalla(provider) => {
+                provider
+                    .duration_matrix(sources, destinations, departure)
+                    .await
+            }
+            AnyRoutingProvider::StraightLine(provider) => {
+                provider
+                    .duration_matrix(sources, destinations, departure)
+                    .await
+            }
+        }
+    }
+
+    async fn isochrone(&self, origin: Point, seconds: i64) -> Result<Option<MultiPolygon>> {
+        match self {
+            AnyRoutingProvider::Google(provider) => provider.isochrone(origin, seconds).await,
+            AnyRoutingProvider::Osrm(provider) => provider.isochrone(origin, seconds).await,
+            AnyRoutingProvider::Valhalla(provider) => provider.isochrone(origin, seconds).await,
+            AnyRoutingProvider::StraightLine(provider) => provider.isochrone(origin, seconds).await,
+        }
+    }
+}
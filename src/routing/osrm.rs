@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result};
+use geo_types::Point;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::upstream::Upstreams;
+
+use super::{DriveDuration, DurationMatrix, RoutingProvider};
+
+/// An OSRM server's table service, using its driving profile.
+/// OSRM has no traffic, so departure times make no difference.
+pub struct Osrm {
+    base_url: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Osrm {
+    pub fn new(base_url: String, upstreams: Arc<Upstreams>) -> Self {
+        Osrm {
+            base_url,
+            upstreams,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OsrmTable {
+    code: String,
+    //Seconds, null where there's no route
+    #[serde(default)]
+    durations: Vec<Vec<Option<f64>>>,
+}
+
+/// Semicolon separated indices from start to start + count
+fn indices(start: usize, count: usize) -> String {
+    (start..start + count)
+        .map(|i| i.to_string())
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+impl RoutingProvider for Osrm {
+    // OSRM's default max-table-size is 100 coordinates, including the single other point
+    fn max_batch(&self) -> usize {
+        99
+    }
+
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        _departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix> {
+        let coordinates = sources
+            .iter()
+            .chain(destinations)
+            .map(|p| format!("{},{}", p.x(), p.y()))
+            .collect::<Vec<String>>()
+            .join(";");
+        let table = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .get(format!("{}/table/v1/driving/{coordinates}", self.base_url))
+                    .query(&[
+                        ("sources", indices(0, sources.len())),
+                        ("destinations", indices(sources.len(), destinations.len())),
+                        ("annotations", String::from("duration")),
+                    ]),
+            )
+            .await?
+            .json::<OsrmTable>()
+            .await?;
+        if table.code != "Ok" {
+            return Err(eyre!("OSRM table failed: {}", table.code));
+        }
+        Ok(table
+            .durations
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|d| d.map(DriveDuration::untrafficked))
+                    .collect()
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,63 @@
+use color_eyre::eyre::Result;
+use geo::{HaversineDestination, VincentyDistance};
+use geo_types::{LineString, MultiPolygon, Point, Polygon};
+use time::OffsetDateTime;
+
+use super::{DriveDuration, DurationMatrix, RoutingProvider};
+
+/// Average speed assumed along the straight line, in metres per second (60km/h)
+const DEFAULT_SPEED: f64 = 16.7;
+/// Vertices around the circle standing in for an isochrone
+const ISOCHRONE_VERTICES: usize = 64;
+
+/// Estimates drive times from straight line distance at a constant speed, needing no routing server.
+/// Only rough, but useful offline and for testing.
+pub struct StraightLine {
+    //Metres per second
+    speed: f64,
+}
+
+impl StraightLine {
+    pub fn new() -> Self {
+        StraightLine {
+            speed: DEFAULT_SPEED,
+        }
+    }
+}
+
+impl RoutingProvider for StraightLine {
+    fn max_batch(&self) -> usize {
+        usize::MAX
+    }
+
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        _departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix> {
+        Ok(sources
+            .iter()
+            .map(|source| {
+                destinations
+                    .iter()
+                    .map(|destination| {
+                        let distance = source.vincenty_distance(destination).ok()?;
+                        Some(DriveDuration::untrafficked(distance / self.speed))
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    async fn isochrone(&self, origin: Point, seconds: i64) -> Result<Option<MultiPolygon>> {
+        let radius = seconds as f64 * self.speed;
+        let ring: LineString = (0..=ISOCHRONE_VERTICES)
+            .map(|i| {
+                let bearing = i as f64 * 360.0 / ISOCHRONE_VERTICES as f64;
+                origin.haversine_destination(bearing, radius)
+            })
+            .collect();
+        Ok(Some(MultiPolygon(vec![Polygon::new(ring, Vec::new())])))
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::Result;
+use geo_types::{Geometry, MultiPolygon, Point};
+use geojson::FeatureCollection;
+use serde::Deserialize;
+use serde_json::json;
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+use crate::upstream::Upstreams;
+
+use super::{DriveDuration, DurationMatrix, RoutingProvider};
+
+/// Valhalla's date_time format, eg 2026-10-18T06:00
+const VALHALLA_DATE_TIME: &[FormatItem] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]");
+
+/// A Valhalla server's matrix service, using its auto costing.
+/// Departure times use its historical traffic speeds where the server has them, it has no pessimistic model.
+pub struct Valhalla {
+    base_url: String,
+    upstreams: Arc<Upstreams>,
+}
+
+impl Valhalla {
+    pub fn new(base_url: String, upstreams: Arc<Upstreams>) -> Self {
+        Valhalla {
+            base_url,
+            upstreams,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ValhallaMatrix {
+    sources_to_targets: Vec<Vec<ValhallaCell>>,
+}
+
+#[derive(Deserialize)]
+struct ValhallaCell {
+    //Seconds, null where there's no route
+    time: Option<f64>,
+}
+
+fn locations(points: &[Point]) -> serde_json::Value {
+    points
+        .iter()
+        .map(|p| json!({ "lat": p.y(), "lon": p.x() }))
+        .collect()
+}
+
+impl RoutingProvider for Valhalla {
+    // Valhalla's default limit is 50 locations, including the single other point
+    fn max_batch(&self) -> usize {
+        49
+    }
+
+    async fn duration_matrix(
+        &self,
+        sources: &[Point],
+        destinations: &[Point],
+        departure: Option<OffsetDateTime>,
+    ) -> Result<DurationMatrix> {
+        let mut request = json!({
+            "sources": locations(sources),
+            "targets": locations(destinations),
+            "costing": "auto",
+        });
+        if let Some(departure) = departure {
+            // Type 1 is depart at, in local time at the sources. Valhalla takes no offset,
+            // so the time is sent as given, in the offset the client gave, which should be the sources'.
+            request["date_time"] = json!({
+                "type": 1,
+                "value": departure.format(VALHALLA_DATE_TIME)?,
+            });
+        }
+        let matrix = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .post(format!("{}/sources_to_targets", self.base_url))
+                    .json(&request),
+            )
+            .await?
+            .json::<ValhallaMatrix>()
+            .await?;
+        Ok(matrix
+            .sources_to_targets
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|cell| cell.time.map(DriveDuration::untrafficked))
+                    .collect()
+            })
+            .collect())
+    }
+
+    async fn isochrone(&self, origin: Point, seconds: i64) -> Result<Option<MultiPolygon>> {
+        let contours = self
+            .upstreams
+            .send(
+                self.upstreams
+                    .post(format!("{}/isochrone", self.base_url))
+                    .json(&json!({
+                        "locations": locations(&[origin]),
+                        "costing": "auto",
+                        "contours": [{ "time": seconds as f64 / 60.0 }],
+                        "polygons": true,
+                    })),
+            )
+            .await?
+            .json::<FeatureCollection>()
+            .await?;
+        let polygons = contours
+            .features
+            .into_iter()
+            .filter_map(|feature| Geometry::<f64>::try_from(feature.geometry?.value).ok())
+            .flat_map(|geometry| match geometry {
+                Geometry::Polygon(polygon) => vec![polygon],
+                Geometry::MultiPolygon(multi) => multi.0,
+                _ => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        Ok((!polygons.is_empty()).then_some(MultiPolygon(polygons)))
+    }
+}
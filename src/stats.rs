@@ -0,0 +1,124 @@
+use color_eyre::eyre::Result;
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::types::dto::stats::{
+    LongestRide, PeriodDistance, RegionDistance, RiddenRoad, Stats, SurfaceDistance,
+};
+
+/// Roads listed in most_ridden_roads
+const MOST_RIDDEN_ROADS: i64 = 10;
+
+/// Statistics over every ride by the given users
+#[instrument(skip(pool))]
+pub async fn riding_stats(pool: &PgPool, user_ids: &[String]) -> Result<Stats> {
+    let totals = sqlx::query!(
+        r#"select
+        coalesce(sum(total_distance), 0)::double precision as "total_distance!",
+        count(*) as "rides!"
+        from rides
+        where user_id = any($1)"#,
+        user_ids
+    )
+    .fetch_one(pool)
+    .await?;
+    let by_year = sqlx::query_as!(
+        PeriodDistance,
+        r#"select
+        extract(year from ridden_at)::int as "year!",
+        null::int as "month",
+        sum(total_distance)::double precision as "distance!",
+        count(*) as "rides!"
+        from rides
+        where user_id = any($1)
+        group by 1
+        order by 1"#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let by_month = sqlx::query_as!(
+        PeriodDistance,
+        r#"select
+        extract(year from ridden_at)::int as "year!",
+        extract(month from ridden_at)::int as "month",
+        sum(total_distance)::double precision as "distance!",
+        count(*) as "rides!"
+        from rides
+        where user_id = any($1)
+        group by 1, 2
+        order by 1, 2"#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let by_surface = sqlx::query_as!(
+        SurfaceDistance,
+        r#"select
+        ride_ways.surface,
+        sum(ride_ways.distance) as "distance!"
+        from ride_ways join rides on rides.id = ride_ways.ride_id
+        where rides.user_id = any($1)
+        group by 1
+        order by 2 desc"#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let by_region = sqlx::query_as!(
+        RegionDistance,
+        r#"select
+        start_address->>'country' as "country",
+        start_address->>'state' as "state",
+        sum(total_distance)::double precision as "distance!",
+        count(*) as "rides!"
+        from rides
+        where user_id = any($1)
+        group by 1, 2
+        order by 3 desc"#,
+        user_ids
+    )
+    .fetch_all(pool)
+    .await?;
+    let longest_ride = sqlx::query_as!(
+        LongestRide,
+        r#"select
+        id,
+        name,
+        total_distance::double precision as "total_distance!"
+        from rides
+        where user_id = any($1)
+        order by total_distance desc
+        limit 1"#,
+        user_ids
+    )
+    .fetch_optional(pool)
+    .await?;
+    let most_ridden_roads = sqlx::query_as!(
+        RiddenRoad,
+        r#"select
+        ride_ways.osm_id,
+        max(ride_ways.name) as "name",
+        count(distinct rides.id) as "rides!",
+        sum(ride_ways.distance) as "distance!"
+        from ride_ways join rides on rides.id = ride_ways.ride_id
+        where rides.user_id = any($1)
+        group by 1
+        order by 3 desc, 4 desc
+        limit $2"#,
+        user_ids,
+        MOST_RIDDEN_ROADS
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Stats {
+        total_distance: totals.total_distance,
+        rides: totals.rides,
+        by_year,
+        by_month,
+        by_surface,
+        by_region,
+        longest_ride,
+        most_ridden_roads,
+    })
+}
@@ -0,0 +1,76 @@
+use std::{
+    net::TcpListener,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{header, StatusCode, Uri},
+    response::IntoResponse,
+    Router,
+};
+
+use crate::{config::UpstreamConfig, upstream::Upstreams};
+
+/// A local HTTP server standing in for an upstream service in tests.
+/// Answers each path with its canned JSON body, and 404 for any other, recording every request.
+pub struct StubServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Uri>>>,
+}
+
+impl StubServer {
+    pub fn start(responses: &[(&'static str, &'static str)]) -> Self {
+        let responses = responses.to_vec();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let app = Router::new().fallback(move |uri: Uri| {
+            let response = responses
+                .iter()
+                .find(|(path, _)| *path == uri.path())
+                .map(|(_, body)| *body);
+            recorded.lock().unwrap().push(uri);
+            async move {
+                match response {
+                    Some(body) => {
+                        ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+                    }
+                    None => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        StubServer { url, requests }
+    }
+
+    /// Every request so far, in order
+    pub fn requests(&self) -> Vec<Uri> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The query of the only request so far, parsed into its pairs
+    pub fn only_query(&self) -> Vec<(String, String)> {
+        let requests = self.requests();
+        assert_eq!(requests.len(), 1, "Expected one request, got {requests:?}");
+        reqwest::Url::parse(&format!("{}{}", self.url, requests[0]))
+            .unwrap()
+            .query_pairs()
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect()
+    }
+}
+
+/// Upstreams with the default limits, for clients under test to call stubs through
+pub fn upstreams() -> Arc<Upstreams> {
+    Arc::new(Upstreams::new(&UpstreamConfig::default()).unwrap())
+}
+
+/// A query pair, to compare with StubServer::only_query
+pub fn pair(key: &str, value: &str) -> (String, String) {
+    (key.to_string(), value.to_string())
+}
@@ -0,0 +1,285 @@
+use std::f64::consts::PI;
+
+use geo_types::{Geometry, LineString, MultiLineString, Point};
+use geozero::{
+    mvt::{
+        tile::{Feature, Layer, Value},
+        Message, TagsBuilder, Tile,
+    },
+    ToMvt,
+};
+
+use crate::cache::Cache;
+
+pub use geozero::mvt::TileValue;
+
+/// Resolution of each tile's coordinate grid
+const EXTENT: u32 = 4096;
+/// Points far outside the tile are pulled in to here, so those near the poles stay finite
+const MAX_COORD: f64 = (1 << 24) as f64;
+/// How far past the tile's edge, in tile coordinates, lines are kept so they join up between tiles
+const BUFFER: f64 = 64.0;
+/// Tiles kept in the cache before it's emptied
+pub const CACHE_CAPACITY: usize = 10_000;
+
+/// Encoded tiles, to be cleared whenever rides change
+pub type TileCache = Cache<TileId, Vec<u8>>;
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct TileId {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl TileId {
+    /// Position in world tile units, where the whole web mercator world is 2^z tiles across
+    fn world(&self, point: &Point) -> (f64, f64) {
+        let n = 2f64.powi(self.z as i32);
+        let lat = point.y().to_radians();
+        let x = (point.x() + 180.0) / 360.0 * n;
+        let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * n;
+        (x, y)
+    }
+
+    /// Position in this tile's coordinate grid, which may fall outside the tile
+    fn tile_coords(&self, point: &Point) -> (f64, f64) {
+        let (x, y) = self.world(point);
+        (
+            (x - self.x as f64) * EXTENT as f64,
+            (y - self.y as f64) * EXTENT as f64,
+        )
+    }
+
+    fn lon(&self, x: f64) -> f64 {
+        x / 2f64.powi(self.z as i32) * 360.0 - 180.0
+    }
+
+    fn lat(&self, y: f64) -> f64 {
+        let n = PI - 2.0 * PI * y / 2f64.powi(self.z as i32);
+        n.sinh().atan().to_degrees()
+    }
+
+    /// The tile's extent in degrees as [west, south, east, north], widened by the buffer
+    /// so rides which only pass through the buffer are still drawn
+    pub fn buffered_bounds(&self) -> [f64; 4] {
+        let buffer = BUFFER / EXTENT as f64;
+        let (x, y) = (self.x as f64, self.y as f64);
+        [
+            self.lon(x - buffer),
+            self.lat(y + 1.0 + buffer),
+            self.lon(x + 1.0 + buffer),
+            self.lat(y - buffer),
+        ]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.z <= 22 && self.x < 1 << self.z && self.y < 1 << self.z
+    }
+}
+
+/// A line feature to be drawn into a tile
+pub struct TileFeature {
+    pub id: u64,
+    pub lines: Vec<LineString>,
+    pub properties: Vec<(&'static str, TileValue)>,
+}
+
+/// The part of the segment from a to b within the buffered tile, by Liang-Barsky
+fn clip_segment(a: (f64, f64), b: (f64, f64)) -> Option<((f64, f64), (f64, f64))> {
+    let (min, max) = (-BUFFER, EXTENT as f64 + BUFFER);
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    // Fractions of the way along the segment it enters and leaves the tile
+    let (mut enter, mut leave) = (0.0f64, 1.0f64);
+    for (p, q) in [
+        (-dx, a.0 - min),
+        (dx, max - a.0),
+        (-dy, a.1 - min),
+        (dy, max - a.1),
+    ] {
+        if p == 0.0 {
+            // Parallel to this edge, and outside it
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            enter = enter.max(q / p);
+        } else {
+            leave = leave.min(q / p);
+        }
+        if enter > leave {
+            return None;
+        }
+    }
+    Some((
+        (a.0 + enter * dx, a.1 + enter * dy),
+        (a.0 + leave * dx, a.1 + leave * dy),
+    ))
+}
+
+/// Split a line into the runs within the buffered tile, cutting segments at its edge so lines
+/// crossing the tile are kept even when none of their points are in it
+fn clip(line: &[(f64, f64)]) -> Vec<Vec<(i32, i32)>> {
+    let round = |(x, y): (f64, f64)| (x.round() as i32, y.round() as i32);
+    let mut runs: Vec<Vec<(i32, i32)>> = Vec::new();
+    let mut run: Vec<(i32, i32)> = Vec::new();
+    for segment in line.windows(2) {
+        match clip_segment(segment[0], segment[1]) {
+            Some((start, end)) => {
+                let (start, end) = (round(start), round(end));
+                // Starting somewhere other than where the last segment ended means it was cut, so the line left the tile
+                if run.last() != Some(&start) {
+                    runs.push(std::mem::take(&mut run));
+                    run.push(start);
+                }
+                if run.last() != Some(&end) {
+                    run.push(end);
+                }
+            }
+            None => runs.push(std::mem::take(&mut run)),
+        }
+    }
+    runs.push(run);
+    runs.into_iter().filter(|run| run.len() >= 2).collect()
+}
+
+/// The parts of the lines within the buffered tile as MVT geometry, or None if none of them cross it
+fn encode_lines(tile: &TileId, lines: &[LineString]) -> Option<Feature> {
+    let runs: Vec<LineString<f64>> = lines
+        .iter()
+        .flat_map(|line| {
+            let coords: Vec<(f64, f64)> = line
+                .points()
+                .map(|p| {
+                    let (x, y) = tile.tile_coords(&p);
+                    (
+                        x.clamp(-MAX_COORD, MAX_COORD),
+                        y.clamp(-MAX_COORD, MAX_COORD),
+                    )
+                })
+                .collect();
+            clip(&coords)
+        })
+        .map(|run| run.into_iter().map(|(x, y)| (x as f64, y as f64)).collect())
+        .collect();
+    if runs.is_empty() {
+        return None;
+    }
+    // Already in tile coordinates, y down, so they're written as they are
+    Geometry::MultiLineString(MultiLineString(runs))
+        .to_mvt_unscaled()
+        .ok()
+}
+
+/// Encode the features as a Mapbox Vector Tile with a single layer
+pub fn encode_tile(tile: &TileId, layer_name: &str, features: &[TileFeature]) -> Vec<u8> {
+    let mut tags = TagsBuilder::<&'static str>::new();
+    let features = features
+        .iter()
+        .filter_map(|feature| {
+            let mut encoded = encode_lines(tile, &feature.lines)?;
+            encoded.id = Some(feature.id);
+            for (key, value) in feature.properties.iter() {
+                let (key, value) = tags.insert(key, value.clone());
+                encoded.tags.extend([key, value]);
+            }
+            Some(encoded)
+        })
+        .collect();
+    let (keys, values) = tags.into_tags();
+    Tile {
+        layers: vec![Layer {
+            version: 2,
+            name: layer_name.to_string(),
+            features,
+            keys: keys.into_iter().map(String::from).collect(),
+            values: values.into_iter().map(Value::from).collect(),
+            extent: Some(EXTENT),
+        }],
+    }
+    .encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geozero::mvt::tile::GeomType;
+
+    fn decode_layer(data: &[u8]) -> Layer {
+        let mut tile = Tile::decode(data).unwrap();
+        assert_eq!(tile.layers.len(), 1);
+        tile.layers.remove(0)
+    }
+
+    #[test]
+    fn clip_keeps_segment_crossing_tile() {
+        let runs = clip(&[(-1000.0, 2048.0), (5000.0, 2048.0)]);
+        assert_eq!(runs, vec![vec![(-64, 2048), (4160, 2048)]]);
+    }
+
+    #[test]
+    fn clip_splits_line_leaving_and_reentering() {
+        let runs = clip(&[
+            (100.0, 100.0),
+            (100.0, -1000.0),
+            (200.0, -1000.0),
+            (200.0, 100.0),
+        ]);
+        assert_eq!(
+            runs,
+            vec![vec![(100, 100), (100, -64)], vec![(200, -64), (200, 100)]]
+        );
+    }
+
+    #[test]
+    fn clip_drops_segment_outside_tile() {
+        assert!(clip(&[(-1000.0, -1000.0), (-1000.0, 5000.0)]).is_empty());
+    }
+
+    #[test]
+    fn buffered_bounds_reach_past_the_tile() {
+        let tile = TileId { z: 1, x: 1, y: 0 };
+        let [west, south, east, north] = tile.buffered_bounds();
+        // The buffer is 64 of the tile's 4096 units, and the tile is 180° wide
+        assert!((west + 180.0 * 64.0 / 4096.0).abs() < 1e-9);
+        assert!((east - 180.0 - 180.0 * 64.0 / 4096.0).abs() < 1e-9);
+        assert!(south < 0.0);
+        assert!(north > tile.lat(0.0));
+    }
+
+    #[test]
+    fn encodes_line_feature() {
+        let tile = TileId { z: 0, x: 0, y: 0 };
+        let feature = TileFeature {
+            id: 7,
+            lines: vec![LineString::from(vec![(-90.0, 0.0), (90.0, 0.0)])],
+            properties: vec![("name", TileValue::Str(String::from("Loop")))],
+        };
+        let layer = decode_layer(&encode_tile(&tile, "rides", &[feature]));
+
+        assert_eq!(layer.version, 2);
+        assert_eq!(layer.name, "rides");
+        assert_eq!(layer.extent, Some(EXTENT));
+        assert_eq!(layer.keys, vec!["name"]);
+        assert_eq!(layer.values[0].string_value.as_deref(), Some("Loop"));
+        assert_eq!(layer.features.len(), 1);
+        let feature = &layer.features[0];
+        assert_eq!(feature.id, Some(7));
+        assert_eq!(feature.tags, vec![0, 0]);
+        assert_eq!(feature.r#type(), GeomType::Linestring);
+        // MoveTo the line's start then LineTo its end, zigzag encoded relative to the last point
+        assert_eq!(feature.geometry, vec![9, 2048, 4096, 10, 4096, 0]);
+    }
+
+    #[test]
+    fn skips_features_outside_tile() {
+        let tile = TileId { z: 2, x: 0, y: 0 };
+        let feature = TileFeature {
+            id: 1,
+            lines: vec![LineString::from(vec![(100.0, -40.0), (120.0, -40.0)])],
+            properties: Vec::new(),
+        };
+        let layer = decode_layer(&encode_tile(&tile, "rides", &[feature]));
+        assert!(layer.features.is_empty());
+    }
+}
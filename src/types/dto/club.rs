@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of users who can see each other's riding statistics
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Club {
+    pub id: i64,
+    pub name: String,
+    pub members: Vec<String>,
+    //Users asked to join who haven't accepted yet, they see none of the members' stats until they do
+    pub invited: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewClub {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewMember {
+    //Kratos identity of the user to invite
+    pub user_id: String,
+}
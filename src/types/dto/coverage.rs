@@ -0,0 +1,55 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// Smallest heatmap cell, about 100m, so a long ride can't cross millions of them
+const MIN_CELL_SIZE: f64 = 0.001;
+
+/// Query parameters for a user's coverage
+#[derive(Deserialize, Clone, Debug)]
+pub struct CoverageQuery {
+    /// Size in degrees of each heatmap cell
+    #[serde(default = "default_cell_size")]
+    pub cell_size: f64,
+}
+
+impl CoverageQuery {
+    /// The cell size is divided by, so must be positive, and not so small every ride crosses countless cells
+    pub fn validate(&self) -> Result<()> {
+        if !(self.cell_size.is_finite() && self.cell_size >= MIN_CELL_SIZE) {
+            return Err(eyre!(
+                "cell_size must be a number of at least {MIN_CELL_SIZE}, got {}",
+                self.cell_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_cell_size() -> f64 {
+    0.05
+}
+
+/// The roads a user has ridden across all of their rides
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Coverage {
+    //Distance in metres of unique ways ridden
+    pub total_distance: f64,
+    pub unique_ways: i64,
+    pub regions: Vec<RegionCoverage>,
+    pub heatmap: Vec<HeatmapCell>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegionCoverage {
+    pub region: Option<String>,
+    pub distance: f64,
+    pub unique_ways: i64,
+}
+
+/// Number of the user's rides passing through a cell, identified by its south west corner
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeatmapCell {
+    pub lon: f64,
+    pub lat: f64,
+    pub rides: i64,
+}
@@ -0,0 +1,31 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::Deserialize;
+use time::{Duration, OffsetDateTime};
+
+/// Times this far in the past are still accepted, for clients whose clocks are a little behind
+const PAST_TOLERANCE: Duration = Duration::minutes(5);
+
+/// When the rider plans to drive to and from a ride, for drive times in traffic at those times.
+/// Valhalla takes times as local to where the drive starts, so they should be given in that place's offset.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+pub struct DriveTimeQuery {
+    /// Leaving the origin for the ride's start, RFC 3339
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub depart_at: Option<OffsetDateTime>,
+    /// Leaving the ride's end for the origin, RFC 3339
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub return_at: Option<OffsetDateTime>,
+}
+
+impl DriveTimeQuery {
+    /// Traffic can only be predicted for drives yet to come
+    pub fn validate(&self) -> Result<()> {
+        let earliest = OffsetDateTime::now_utc() - PAST_TOLERANCE;
+        for (name, time) in [("depart_at", self.depart_at), ("return_at", self.return_at)] {
+            if let Some(time) = time.filter(|time| *time < earliest) {
+                return Err(eyre!("{name} {time} is in the past"));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// A place found by searching for it by name
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GeocodedPlace {
+    pub display_name: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct GeocodeQuery {
+    pub q: String,
+    /// Area to prefer results in, as min lon,min lat,max lon,max lat
+    #[serde(default)]
+    pub viewbox: Option<String>,
+    /// Comma separated ISO 3166-1 alpha-2 codes of countries to limit results to, eg au,nz
+    #[serde(default)]
+    pub countrycodes: Option<String>,
+}
+
+impl GeocodeQuery {
+    pub fn filters(&self) -> Result<SearchFilters> {
+        let viewbox = self
+            .viewbox
+            .as_deref()
+            .map(|viewbox| {
+                let bounds = viewbox
+                    .split(',')
+                    .map(|n| n.trim().parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()?;
+                <[f64; 4]>::try_from(bounds)
+                    .map_err(|_| eyre!("viewbox needs 4 numbers, got {viewbox}"))
+            })
+            .transpose()?;
+        let country_codes = self
+            .countrycodes
+            .as_deref()
+            .map(|codes| {
+                codes
+                    .split(',')
+                    .map(|code| code.trim().to_lowercase())
+                    .filter(|code| !code.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(SearchFilters {
+            viewbox,
+            country_codes,
+        })
+    }
+}
+
+/// Narrows a place search
+#[derive(Default, Debug)]
+pub struct SearchFilters {
+    /// Results in here are preferred, as [min lon, min lat, max lon, max lat]
+    pub viewbox: Option<[f64; 4]>,
+    /// Lowercase ISO 3166-1 alpha-2 codes, results outside them are left out. Empty for any country.
+    pub country_codes: Vec<String>,
+}
+
+impl SearchFilters {
+    /// Centre of the viewbox, for geocoders which bias towards a point instead
+    pub fn focus(&self) -> Option<(f64, f64)> {
+        self.viewbox.map(|[min_lon, min_lat, max_lon, max_lat]| {
+            ((min_lon + max_lon) / 2.0, (min_lat + max_lat) / 2.0)
+        })
+    }
+}
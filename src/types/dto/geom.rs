@@ -1,17 +1,118 @@
+use geo_types::Point;
+use geojson::GeoJson;
 use google_maps::{prelude::Decimal, LatLng};
+use num_traits::ToPrimitive;
 use serde::Deserialize;
+use sqlx::types::Json;
+
+use crate::ride_geo::{simplify_to_budget, zoom_tolerance, SimplifyLines};
 
 #[derive(Deserialize, Clone)]
 pub struct PartialLatLng {
     pub lat: Option<Decimal>,
     pub lon: Option<Decimal>,
+    /// Free text to look the origin up by, eg a town name, when lat and lon aren't given
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// One of the user's saved origins, when lat and lon aren't given
+    #[serde(default)]
+    pub origin_id: Option<i64>,
 }
 
-impl From<PartialLatLng> for Option<LatLng> {
+/// None unless both lat and lon are given and in range
+impl From<PartialLatLng> for Option<Point> {
     fn from(value: PartialLatLng) -> Self {
         match (value.lat, value.lon) {
-            (Some(lat), Some(lon)) => LatLng::try_from_dec(lat, lon).ok(),
+            (Some(lat), Some(lon)) => {
+                LatLng::try_from_dec(lat, lon).ok()?;
+                Some(Point::new(lon.to_f64()?, lat.to_f64()?))
+            }
             _ => None,
         }
     }
 }
+
+/// Encoding for a ride's geometry in responses
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GeometryFormat {
+    #[default]
+    GeoJson,
+    /// Google encoded polyline, precision 5
+    Polyline5,
+    /// Google encoded polyline, precision 6
+    Polyline6,
+    TopoJson,
+    /// Binary FlatGeobuf, returned as the whole response body without ride metadata
+    FlatGeobuf,
+}
+
+/// Query parameters selecting how, or whether, a ride's geometry is returned
+#[derive(Deserialize, Clone, Debug)]
+pub struct GeometryQuery {
+    #[serde(default)]
+    pub format: Option<GeometryFormat>,
+    #[serde(default)]
+    pub geometry: Option<GeometryInclusion>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum GeometryInclusion {
+    Full,
+    None,
+}
+
+impl GeometryQuery {
+    /// Format to respond with, from the format parameter or else the Accept header.
+    /// None if the client asked for no geometry.
+    pub fn format(&self, accept: Option<&str>) -> Option<GeometryFormat> {
+        if self.geometry == Some(GeometryInclusion::None) {
+            return None;
+        }
+        Some(self.format.unwrap_or_else(|| match accept {
+            Some(accept) if accept.contains("application/flatgeobuf") => GeometryFormat::FlatGeobuf,
+            Some(accept) if accept.contains("application/topo+json") => GeometryFormat::TopoJson,
+            _ => GeometryFormat::GeoJson,
+        }))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SimplifyAlgorithm {
+    #[default]
+    DouglasPeucker,
+    Visvalingam,
+}
+
+/// Query parameters for simplifying a ride's geometry before it's returned
+#[derive(Deserialize, Clone, Debug)]
+pub struct SimplifyQuery {
+    /// Web map zoom level the geometry will be drawn at, used to derive a tolerance
+    #[serde(default)]
+    pub zoom: Option<f64>,
+    /// Tolerance in metres, overrides zoom
+    #[serde(default)]
+    pub tolerance_m: Option<f64>,
+    /// Simplify further until the geometry has at most this many points
+    #[serde(default)]
+    pub max_points: Option<usize>,
+    #[serde(default)]
+    pub simplify: SimplifyAlgorithm,
+}
+
+impl SimplifyQuery {
+    /// Simplify the geometry as requested, returning it unchanged if no simplification was asked for
+    pub fn apply(&self, geo_json: Json<GeoJson>) -> Json<GeoJson> {
+        let tolerance_m = self.tolerance_m.or(self.zoom.map(zoom_tolerance));
+        let geo_json = match tolerance_m {
+            Some(tolerance_m) => Json(geo_json.simplify_lines(tolerance_m, self.simplify)),
+            None => geo_json,
+        };
+        match self.max_points {
+            Some(max_points) => Json(simplify_to_budget(&geo_json.0, max_points, self.simplify)),
+            None => geo_json,
+        }
+    }
+}
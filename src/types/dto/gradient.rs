@@ -0,0 +1,85 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// Query parameters controlling gradient analysis
+#[derive(Deserialize, Clone, Debug)]
+pub struct GradientQuery {
+    /// Distance in metres over which each gradient is measured
+    #[serde(default = "default_gradient_window")]
+    pub gradient_window: f64,
+    /// Gradient percentage above which a section is steep
+    #[serde(default = "default_steep_threshold")]
+    pub steep_threshold: f64,
+    /// Gradient percentage above which a section on dirt is steep
+    #[serde(default = "default_steep_dirt_threshold")]
+    pub steep_dirt_threshold: f64,
+    /// Width in percent of each histogram bucket
+    #[serde(default = "default_histogram_bucket")]
+    pub histogram_bucket: f64,
+}
+
+impl Default for GradientQuery {
+    fn default() -> Self {
+        GradientQuery {
+            gradient_window: default_gradient_window(),
+            steep_threshold: default_steep_threshold(),
+            steep_dirt_threshold: default_steep_dirt_threshold(),
+            histogram_bucket: default_histogram_bucket(),
+        }
+    }
+}
+
+impl GradientQuery {
+    /// The window and bucket are divided by, so must be positive
+    pub fn validate(&self) -> Result<()> {
+        for (name, value) in [
+            ("gradient_window", self.gradient_window),
+            ("histogram_bucket", self.histogram_bucket),
+        ] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(eyre!("{name} must be a positive number, got {value}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_gradient_window() -> f64 {
+    100.0
+}
+
+fn default_steep_threshold() -> f64 {
+    15.0
+}
+
+fn default_steep_dirt_threshold() -> f64 {
+    12.0
+}
+
+fn default_histogram_bucket() -> f64 {
+    2.0
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GradientAnalysis {
+    pub steep_sections: Vec<SteepSection>,
+    pub histogram: Vec<GradientBucket>,
+}
+
+/// A contiguous stretch of the ride steeper than the threshold for its surface.
+/// Distances are in metres along the ride, gradients in percent, negative for descents.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SteepSection {
+    pub start_distance: f64,
+    pub end_distance: f64,
+    pub max_gradient: f64,
+    pub surface: Option<String>,
+}
+
+/// Distance in metres ridden at a gradient between min_gradient and max_gradient
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GradientBucket {
+    pub min_gradient: f64,
+    pub max_gradient: f64,
+    pub distance: f64,
+}
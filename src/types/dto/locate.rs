@@ -0,0 +1,34 @@
+use geo_types::Point;
+use serde::{Deserialize, Serialize};
+
+/// Either distance_m, or lat and lon, to locate on a ride
+#[derive(Deserialize, Clone, Debug)]
+pub struct LocateQuery {
+    #[serde(default)]
+    pub distance_m: Option<f64>,
+    #[serde(default)]
+    pub lat: Option<f64>,
+    #[serde(default)]
+    pub lon: Option<f64>,
+}
+
+/// A position along a ride
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Located {
+    pub point: Point,
+    //Metres along the ride
+    pub distance: f64,
+    //Metres from the requested point to the ride, zero when locating by distance
+    pub offset: f64,
+    pub elevation: Option<f64>,
+    pub way: Option<LocatedWay>,
+}
+
+/// The way a located position is on
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocatedWay {
+    pub osm_id: u64,
+    pub name: Option<String>,
+    pub surface: Option<String>,
+    pub distance: f64,
+}
@@ -1,3 +1,14 @@
+pub mod club;
+pub mod coverage;
+pub mod drive_time;
+pub mod geocode;
 pub mod geom;
+pub mod gradient;
+pub mod locate;
 pub mod nominatim;
+pub mod origin;
+pub mod profile;
 pub mod ride;
+pub mod stats;
+pub mod status;
+pub mod upstream;
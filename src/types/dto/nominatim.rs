@@ -7,48 +7,89 @@ pub struct NominatimPlace {
     pub display_name: String,
     #[serde(default)]
     pub category: Option<String>,
+    //Class within the category, eg the kind of highway
+    #[serde(default, rename = "type")]
+    pub place_type: Option<String>,
     #[serde(default)]
     pub name: Option<String>,
-    pub address: Address,
+    //Absent from places found in a local OSM extract
+    #[serde(default)]
+    pub address: Option<Address>,
     pub extratags: ExtraTags,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl NominatimPlace {
+    /// What geocoders find at points with nothing nearby, eg out at sea. Not a road, and without an address.
+    pub fn nowhere() -> Self {
+        NominatimPlace {
+            osm_type: String::new(),
+            osm_id: 0,
+            display_name: String::new(),
+            category: None,
+            place_type: None,
+            name: None,
+            address: None,
+            extratags: ExtraTags { surface: None },
+        }
+    }
+}
+
+/// Every part is optional, remote places often have no road, and some countries have no states
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
 pub struct Address {
-    pub road: String,
-    #[serde(default)]
+    pub road: Option<String>,
     pub tourism: Option<String>,
-    #[serde(default)]
     pub amenity: Option<String>,
-    #[serde(default)]
     pub suburb: Option<String>,
-    #[serde(default)]
     pub hamlet: Option<String>,
-    #[serde(default)]
+    pub village: Option<String>,
     pub town: Option<String>,
-    #[serde(default)]
     pub city: Option<String>,
-    #[serde(default)]
     pub municipality: Option<String>,
-    pub state: String,
+    pub county: Option<String>,
+    pub state: Option<String>,
     #[serde(rename = "ISO3166-2-lvl4")]
-    pub iso3166_2_lvl4: String,
-    #[serde(default)]
+    pub iso3166_2_lvl4: Option<String>,
     pub postcode: Option<String>,
-    pub country: String,
-    pub country_code: String,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Address {
+    /// The name of the most local place in the address, falling back from locality to county, state then country.
+    /// None if the address has none of them, eg out at sea.
+    pub fn label(&self) -> Option<String> {
+        [
+            &self.city,
+            &self.town,
+            &self.village,
+            &self.hamlet,
+            &self.suburb,
+            &self.municipality,
+            &self.county,
+            &self.state,
+            &self.country,
+        ]
+        .into_iter()
+        .flatten()
+        .next()
+        .cloned()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ExtraTags {
     #[serde(default)]
     pub surface: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NominatimDetailsPlace {
     pub osm_type: String,
     pub osm_id: u64,
+    //Empty for unnamed ways
+    #[serde(default)]
     pub localname: String,
     pub extratags: ExtraTags,
 }
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+/// A place a user has saved to measure drive times from
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Origin {
+    pub id: i64,
+    pub name: String,
+    pub address: Option<String>,
+    pub lat: f64,
+    pub lon: f64,
+    pub is_default: bool,
+}
+
+/// A new or changed origin. It's placed by lat and lon if both are given, otherwise by looking up its address.
+#[derive(Deserialize, Debug)]
+pub struct SaveOrigin {
+    pub name: String,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub lat: Option<f64>,
+    #[serde(default)]
+    pub lon: Option<f64>,
+    #[serde(default)]
+    pub is_default: bool,
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProfileQuery {
+    /// Distance in metres between samples
+    #[serde(default = "default_interval")]
+    pub interval_m: f64,
+}
+
+fn default_interval() -> f64 {
+    100.0
+}
+
+/// A sample of the ride at a distance along it, for charting
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfilePoint {
+    //Metres along the ride
+    pub distance: f64,
+    pub elevation: Option<f64>,
+    //Percent over the sampling interval around this point
+    pub gradient: Option<f64>,
+    pub surface: Option<String>,
+    pub way_name: Option<String>,
+    //km/h since the previous sample, only for recorded rides
+    pub speed: Option<f64>,
+}
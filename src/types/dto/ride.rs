@@ -1,37 +1,143 @@
-use crate::types::model::ride::WayPoint;
+use crate::types::model::ride::{UnmatchedStretch, WayPoint};
+use color_eyre::eyre::{eyre, Result};
 use geojson::GeoJson;
 use serde::{Deserialize, Serialize};
 use sqlx::types::{BigDecimal, Json};
 
-use super::nominatim::{Address, NominatimDetailsPlace};
+use super::{
+    gradient::GradientAnalysis,
+    nominatim::{Address, NominatimDetailsPlace},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ListRide {
     pub id: i64,
     pub name: String,
     pub total_distance: BigDecimal,
-    pub start_address: Json<Address>,
-    pub end_address: Json<Address>,
+    //None when the geocoder couldn't be reached, see warnings
+    pub start_address: Option<Json<Address>>,
+    pub end_address: Option<Json<Address>>,
+    //Short names for the start and end, falling back to wider areas where addresses are sparse
+    pub start_label: Option<String>,
+    pub end_label: Option<String>,
     pub time_from_origin_to_start: Option<i64>,
     pub time_from_end_to_origin: Option<i64>,
+    //Drive times in heavy traffic, when requested with departure times and the provider models traffic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_from_origin_to_start_pessimistic: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_from_end_to_origin_pessimistic: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_geometry: Option<Json<GeoJson>>,
+    //What's missing from the ride because an upstream service failed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Query parameters for listing rides
+#[derive(Deserialize, Clone, Debug)]
+pub struct ListRideQuery {
+    /// Comma separated optional extras to include with each ride, eg preview_geometry
+    #[serde(default)]
+    pub include: Option<String>,
+    /// Only rides whose start is within this many seconds' drive of the origin, nearest first
+    #[serde(default)]
+    pub max_drive_time: Option<i64>,
+}
+
+impl ListRideQuery {
+    pub fn includes(&self, extra: &str) -> bool {
+        self.include
+            .as_deref()
+            .is_some_and(|include| include.split(',').any(|i| i.trim() == extra))
+    }
+
+    /// A drive time which isn't positive can't reach any ride
+    pub fn validate(&self) -> Result<()> {
+        match self.max_drive_time {
+            Some(max_drive_time) if max_drive_time <= 0 => Err(eyre!(
+                "max_drive_time must be a positive number of seconds, got {max_drive_time}"
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Ride {
     pub id: i64,
     pub name: String,
-    pub geo_json: Json<GeoJson>,
+    //Absent if the client asked for no geometry
+    #[serde(flatten)]
+    pub geometry: Option<RideGeometry>,
+    //Ordered segments of the ride, one per stretch of road
     pub ways: Json<Vec<RideWay>>,
+    //The segments grouped by road
+    pub roads: Vec<RoadSummary>,
+    //Stretches of the ride not on any road
+    pub unmatched: Vec<UnmatchedStretch>,
+    pub reconciliation: Reconciliation,
     pub total_distance: BigDecimal,
-    pub start_address: Json<Address>,
-    pub end_address: Json<Address>,
+    //None when the geocoder couldn't be reached, see warnings
+    pub start_address: Option<Json<Address>>,
+    pub end_address: Option<Json<Address>>,
+    //Short names for the start and end, falling back to wider areas where addresses are sparse
+    pub start_label: Option<String>,
+    pub end_label: Option<String>,
     pub time_from_origin_to_start: Option<i64>,
     pub time_from_end_to_origin: Option<i64>,
+    //Drive times in heavy traffic, when requested with departure times and the provider models traffic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_from_origin_to_start_pessimistic: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_from_end_to_origin_pessimistic: Option<i64>,
+    //None if the ride has no elevation data
+    pub gradient: Option<GradientAnalysis>,
+    //What's missing from the ride because an upstream service failed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// The ride's geometry, keyed by the format it's encoded in
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RideGeometry {
+    GeoJson(Json<GeoJson>),
+    //One encoded polyline per track line
+    Polyline(Vec<String>),
+    TopoJson(serde_json::Value),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RideWay {
+    //Name, or highway class and osm id for unnamed roads
+    pub label: String,
+    //Metres along the ride
+    pub start_distance: f64,
+    pub end_distance: f64,
     pub distance: f64,
     pub points: Vec<WayPoint>,
-    pub place: NominatimDetailsPlace,
+    //None if the way's details couldn't be looked up
+    pub place: Option<NominatimDetailsPlace>,
+}
+
+/// Total distance on a road across every segment of the ride on it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoadSummary {
+    pub label: String,
+    pub name: Option<String>,
+    pub osm_ids: Vec<u64>,
+    pub distance: f64,
+    //Number of separate times the ride is on the road
+    pub segments: usize,
+}
+
+/// How the ride's total distance splits between roads and stretches on no road.
+/// Difference is whatever neither accounts for, eg gaps between separately recorded tracks.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reconciliation {
+    pub total_distance: f64,
+    pub matched_distance: f64,
+    pub unmatched_distance: f64,
+    pub difference: f64,
 }
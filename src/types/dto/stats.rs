@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// Query parameters for riding statistics
+#[derive(Deserialize, Clone, Debug)]
+pub struct StatsQuery {
+    /// Statistics for every member of this club, rather than just the current user
+    #[serde(default)]
+    pub club_id: Option<i64>,
+}
+
+/// Riding statistics, distances in metres
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stats {
+    pub total_distance: f64,
+    pub rides: i64,
+    pub by_year: Vec<PeriodDistance>,
+    pub by_month: Vec<PeriodDistance>,
+    pub by_surface: Vec<SurfaceDistance>,
+    pub by_region: Vec<RegionDistance>,
+    pub longest_ride: Option<LongestRide>,
+    pub most_ridden_roads: Vec<RiddenRoad>,
+}
+
+/// Distance ridden in a year, or a month of it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeriodDistance {
+    pub year: i32,
+    pub month: Option<i32>,
+    pub distance: f64,
+    pub rides: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SurfaceDistance {
+    pub surface: Option<String>,
+    pub distance: f64,
+}
+
+/// Distance of rides starting in each country and state
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegionDistance {
+    pub country: Option<String>,
+    pub state: Option<String>,
+    pub distance: f64,
+    pub rides: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LongestRide {
+    pub id: i64,
+    pub name: String,
+    pub total_distance: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RiddenRoad {
+    pub osm_id: i64,
+    pub name: Option<String>,
+    pub rides: i64,
+    pub distance: f64,
+}
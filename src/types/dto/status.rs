@@ -0,0 +1,38 @@
+use serde::Serialize;
+
+use super::upstream::UpstreamStatus;
+
+/// How one of the services the server depends on is doing
+#[derive(Serialize, Debug)]
+pub struct DependencyStatus {
+    //database, geocoder or routing
+    pub name: &'static str,
+    //Whether the server reports unready without it
+    pub required: bool,
+    pub healthy: bool,
+    //Of the check just made, if it got an answer
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+    //Limits, circuit state and latest error of calls to it, for services called over HTTP which have been called
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamStatus>,
+}
+
+/// Whether the server can serve requests, with the checks that decided it
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub dependencies: Vec<DependencyStatus>,
+}
+
+/// The server's build and schema versions, and how its dependencies are doing
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub version: &'static str,
+    //Commit the server was built from, if the build was given one
+    pub git_sha: Option<&'static str>,
+    //Latest migration applied to the database, None if it couldn't be read
+    pub migration_version: Option<i64>,
+    pub ready: bool,
+    pub dependencies: Vec<DependencyStatus>,
+}
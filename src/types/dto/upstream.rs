@@ -0,0 +1,33 @@
+use serde::Serialize;
+
+/// Whether calls to an upstream host are let through
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    //Failing, calls are refused without trying until it's time for a trial call
+    Open,
+    //A trial call has been let through, the rest are refused until it succeeds
+    HalfOpen,
+}
+
+/// How calls to an upstream host are being limited, and how they're going
+#[derive(Serialize, Debug)]
+pub struct UpstreamStatus {
+    pub host: String,
+    pub circuit: CircuitState,
+    pub consecutive_failures: u32,
+    pub max_concurrency: usize,
+    pub in_flight: usize,
+    //Requests per second
+    pub rate_limit: f64,
+    pub tokens_available: f64,
+    //Totals since startup
+    pub attempts: u64,
+    pub failures: u64,
+    pub refused: u64,
+    //Of the latest attempt which got an answer
+    pub last_latency_ms: Option<u64>,
+    //Of the latest failed attempt, kept after the host recovers
+    pub last_error: Option<String>,
+}
@@ -8,6 +8,9 @@ use serde::{Deserialize, Serialize};
 pub struct FeatureProperties {
     pub distance: f64,
     pub name: Option<String>,
+    /// RFC 3339 time of each point, one array per line as with togeojson
+    #[serde(rename = "coordTimes", skip_serializing_if = "Option::is_none")]
+    pub coord_times: Option<Vec<Vec<Option<String>>>>,
 }
 
 /// For converting FeatureProperties to geojson properties
@@ -2,6 +2,7 @@ use geo_types::Point;
 use geojson::GeoJson;
 use serde::{Deserialize, Serialize};
 use sqlx::types::{BigDecimal, Json};
+use time::OffsetDateTime;
 
 use crate::types::dto::nominatim::Address;
 
@@ -10,10 +11,26 @@ use crate::types::dto::nominatim::Address;
 pub struct Ride {
     pub id: Option<i64>,
     pub name: String,
+    //Kratos identity of the user who uploaded the ride
+    pub user_id: Option<String>,
+    pub ridden_at: OffsetDateTime,
     pub geo_json: Json<GeoJson>,
+    //Simplified to a small number of points, for drawing many rides at once
+    pub preview_geo_json: Json<GeoJson>,
     //Total distance in metres
     pub total_distance: BigDecimal,
     pub ways: Json<Vec<RideWay>>,
+    //Stretches of the ride not on any road
+    pub unmatched: Json<Vec<UnmatchedStretch>>,
+    pub start_address: Json<Address>,
+    //Aggregated surface covering the most distance
+    pub surface_class: Option<String>,
+    pub difficulty: Option<String>,
+    //Bounding box in degrees, for finding rides within a map tile
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
 }
 
 //Used when retrieving from db
@@ -23,7 +40,9 @@ pub struct QueryRide {
     pub name: String,
     pub total_distance: BigDecimal,
     pub geo_json: Option<Json<GeoJson>>,
+    pub preview_geo_json: Option<Json<GeoJson>>,
     pub ways: Option<Json<Vec<RideWay>>>,
+    pub unmatched: Option<Json<Vec<UnmatchedStretch>>>,
     pub start_point: Option<Json<Point>>,
     pub end_point: Option<Json<Point>>,
 }
@@ -34,23 +53,92 @@ pub struct ProcessedRide {
     pub name: String,
     pub total_distance: BigDecimal,
     pub geo_json: Option<Json<GeoJson>>,
-    pub ways: Option<Json<Vec<RideWay>>>,
-    pub start_point: Point,
-    pub end_point: Point,
-    pub start_address: Address,
-    pub end_address: Address,
+    pub preview_geo_json: Option<Json<GeoJson>>,
+    //None if the geocoder couldn't be reached
+    pub start_address: Option<Address>,
+    pub end_address: Option<Address>,
     pub time_from_origin_to_start: Option<i64>,
     pub time_from_end_to_origin: Option<i64>,
+    //Only from providers which model traffic, given a departure time
+    pub time_from_origin_to_start_pessimistic: Option<i64>,
+    pub time_from_end_to_origin_pessimistic: Option<i64>,
+    //What couldn't be looked up, for clients to explain missing addresses and drive times
+    pub warnings: Vec<String>,
+}
+
+//Used when drawing rides into map tiles
+pub struct TileRide {
+    pub id: i64,
+    pub name: String,
+    pub surface_class: Option<String>,
+    pub difficulty: Option<String>,
+    pub geo_json: Json<GeoJson>,
 }
 
+/// A contiguous stretch of the ride along one road.
+/// Rides imported before segments were introduced have one per road instead, without start and end distances.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RideWay {
+    //Order of the segment along the ride, counting unmatched stretches.
+    //Seq of the road's first point on rides imported before segments.
     pub seq: u64,
     pub osm_id: u64,
+    //Every place id the road had along the segment
+    #[serde(default)]
+    pub osm_ids: Vec<u64>,
+    //Name, surface and region are absent on rides imported before they were stored
+    #[serde(default)]
+    pub name: Option<String>,
+    //Highway class, eg track or residential
+    #[serde(default)]
+    pub highway: Option<String>,
+    //Aggregated surface
+    #[serde(default)]
+    pub surface: Option<String>,
+    //State the way is in, from its address
+    #[serde(default)]
+    pub region: Option<String>,
+    //Metres along the ride
+    #[serde(default)]
+    pub start_distance: f64,
+    #[serde(default)]
+    pub end_distance: f64,
+    //Length of the segment in metres
     pub distance: f64,
     pub points: Vec<WayPoint>,
 }
 
+impl RideWay {
+    /// The road's name, or for unnamed roads its highway class and osm id, eg "track 123456"
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!(
+                "{} {}",
+                self.highway.as_deref().unwrap_or("way"),
+                self.osm_id
+            ),
+        }
+    }
+}
+
+/// A contiguous stretch of the ride where no road could be found, eg across a field or car park
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnmatchedStretch {
+    //Order of the stretch along the ride, counting segments on roads
+    pub seq: u64,
+    //Metres along the ride
+    pub start_distance: f64,
+    pub end_distance: f64,
+    pub distance: f64,
+}
+
+/// A ride broken down into the segments on each road, and the stretches between them on no road
+pub struct WayBreakdown {
+    pub ways: Vec<RideWay>,
+    pub unmatched: Vec<UnmatchedStretch>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WayPoint {
     pub seq: usize,
@@ -0,0 +1,460 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Report, Result};
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, IntoUrl, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::{
+    sync::Semaphore,
+    time::{sleep, timeout},
+};
+use tracing::warn;
+
+use crate::{
+    config::UpstreamConfig,
+    types::dto::upstream::{CircuitState, UpstreamStatus},
+};
+
+/// Attempts at an upstream call before giving up on it
+const ATTEMPTS: u32 = 4;
+/// Wait before the first retry, doubling after each one
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Longest wait between retries, including one a host asks for
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// Failures in a row that open a host's circuit
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long an open circuit refuses calls before letting a trial call through
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// Requests in flight at once and requests per second allowed to one host
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub concurrency: usize,
+    pub rate: f64,
+}
+
+impl Limits {
+    /// Hosts whose usage policies are stricter than the defaults
+    pub fn for_host(host: &str) -> Self {
+        match host {
+            // https://operations.osmfoundation.org/policies/nominatim/
+            "nominatim.openstreetmap.org" => Limits {
+                concurrency: 1,
+                rate: 1.0,
+            },
+            _ => Limits {
+                concurrency: 8,
+                rate: 20.0,
+            },
+        }
+    }
+}
+
+impl FromStr for Limits {
+    type Err = Report;
+
+    /// Concurrency and rate separated by a slash, eg 4/10
+    fn from_str(s: &str) -> Result<Self> {
+        let (concurrency, rate) = s
+            .split_once('/')
+            .ok_or(eyre!("Expected concurrency/rate, got {s}"))?;
+        let limits = Limits {
+            concurrency: concurrency.trim().parse()?,
+            rate: rate.trim().parse()?,
+        };
+        if limits.concurrency == 0 || limits.rate <= 0.0 {
+            return Err(eyre!("Limits must be above zero, got {s}"));
+        }
+        Ok(limits)
+    }
+}
+
+/// Parse per host limits, eg nominatim.example.com=4/10,router.example.com=16/50
+pub fn parse_limits(s: &str) -> Result<HashMap<String, Limits>> {
+    s.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (host, limits) = entry
+                .split_once('=')
+                .ok_or(eyre!("Expected host=concurrency/rate, got {entry}"))?;
+            Ok((host.trim().to_string(), limits.parse()?))
+        })
+        .collect()
+}
+
+/// Up to a second's worth of requests can be made at once, then they're spread out at the rate
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+struct Breaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    //When the circuit opened, or the last trial call was let through
+    since: Instant,
+}
+
+/// How the latest attempts at calls to a host went
+#[derive(Default)]
+struct LastCall {
+    //Of the latest attempt which got an answer
+    latency: Option<Duration>,
+    //Of the latest failed attempt, kept after the host recovers
+    error: Option<String>,
+}
+
+/// The limits and health of calls to one host, shared by everything calling it
+struct Upstream {
+    host: String,
+    limits: Limits,
+    permits: Semaphore,
+    bucket: Mutex<TokenBucket>,
+    breaker: Mutex<Breaker>,
+    attempts: AtomicU64,
+    failures: AtomicU64,
+    refused: AtomicU64,
+    last: Mutex<LastCall>,
+}
+
+/// The result of one attempt at an upstream call
+pub enum Attempt<T> {
+    Success(T),
+    //The host turned the request down, eg a 404, retrying won't help but the host isn't failing
+    Rejected(Report),
+    //The host failed or asked to slow down, worth retrying after the wait if it gave one
+    Failed(Report, Option<Duration>),
+}
+
+impl Attempt<Response> {
+    /// Judge an HTTP response: 429s, server errors and failures to connect are worth retrying, other errors aren't.
+    /// URLs are left out of the errors, as some carry API keys.
+    pub fn from_response(response: reqwest::Result<Response>) -> Self {
+        match response {
+            Ok(response) => {
+                let status = response.status();
+                if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                    Attempt::Failed(eyre!("Responded {status}"), retry_after(&response))
+                } else {
+                    match response.error_for_status() {
+                        Ok(response) => Attempt::Success(response),
+                        Err(error) => Attempt::Rejected(error.without_url().into()),
+                    }
+                }
+            }
+            Err(error) => Attempt::Failed(error.without_url().into(), None),
+        }
+    }
+}
+
+impl Upstream {
+    fn new(host: &str, limits: Limits) -> Self {
+        Upstream {
+            host: host.to_string(),
+            limits,
+            permits: Semaphore::new(limits.concurrency),
+            bucket: Mutex::new(TokenBucket {
+                tokens: limits.rate.max(1.0),
+                refilled: Instant::now(),
+            }),
+            breaker: Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                since: Instant::now(),
+            }),
+            attempts: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            last: Mutex::new(LastCall::default()),
+        }
+    }
+
+    fn refill(&self, bucket: &mut TokenBucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limits.rate).min(self.limits.rate.max(1.0));
+        bucket.refilled = now;
+    }
+
+    /// Wait until the rate limit allows another request
+    async fn take_token(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().expect("Token bucket poisoned");
+                self.refill(&mut bucket);
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.limits.rate)
+            };
+            sleep(wait).await;
+        }
+    }
+
+    /// Whether the circuit lets a call through now
+    fn admit(&self) -> bool {
+        let mut breaker = self.breaker.lock().expect("Circuit breaker poisoned");
+        match breaker.state {
+            CircuitState::Closed => true,
+            // Only one trial call per open period, so a struggling host isn't swamped as it recovers
+            CircuitState::Open | CircuitState::HalfOpen
+                if breaker.since.elapsed() >= OPEN_DURATION =>
+            {
+                breaker.state = CircuitState::HalfOpen;
+                breaker.since = Instant::now();
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        }
+    }
+
+    fn record_last(&self, latency: Option<Duration>, error: Option<&Report>) {
+        let mut last = self.last.lock().expect("Last call poisoned");
+        if latency.is_some() {
+            last.latency = latency;
+        }
+        if let Some(error) = error {
+            last.error = Some(format!("{error:#}"));
+        }
+    }
+
+    fn record(&self, healthy: bool) {
+        let mut breaker = self.breaker.lock().expect("Circuit breaker poisoned");
+        if healthy {
+            breaker.state = CircuitState::Closed;
+            breaker.consecutive_failures = 0;
+            return;
+        }
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        breaker.consecutive_failures += 1;
+        if breaker.state == CircuitState::HalfOpen
+            || breaker.consecutive_failures >= FAILURE_THRESHOLD
+        {
+            if breaker.state == CircuitState::Closed {
+                warn!(
+                    "Opening circuit to {} after {} failures in a row",
+                    self.host, breaker.consecutive_failures
+                );
+            }
+            breaker.state = CircuitState::Open;
+            breaker.since = Instant::now();
+        }
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        let breaker = self.breaker.lock().expect("Circuit breaker poisoned");
+        let tokens = {
+            let mut bucket = self.bucket.lock().expect("Token bucket poisoned");
+            self.refill(&mut bucket);
+            bucket.tokens
+        };
+        let last = self.last.lock().expect("Last call poisoned");
+        UpstreamStatus {
+            host: self.host.clone(),
+            circuit: breaker.state,
+            consecutive_failures: breaker.consecutive_failures,
+            max_concurrency: self.limits.concurrency,
+            in_flight: self.limits.concurrency - self.permits.available_permits(),
+            rate_limit: self.limits.rate,
+            tokens_available: tokens,
+            attempts: self.attempts.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            last_latency_ms: last.latency.map(|latency| latency.as_millis() as u64),
+            last_error: last.error.clone(),
+        }
+    }
+}
+
+/// The wait to retry after, between half and all of the backoff so callers which failed together don't retry together
+fn jittered(backoff: Duration) -> Duration {
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// The wait a 429 or 503 asks for, when given in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    Some(Duration::from_secs(seconds.trim().parse().ok()?))
+}
+
+/// Every external service called, with the HTTP client they're called through
+pub struct Upstreams {
+    client: reqwest::Client,
+    //Per host overrides of the default limits
+    limits: HashMap<String, Limits>,
+    //Longest a single attempt can take before it's abandoned
+    attempt_timeout: Duration,
+    hosts: Mutex<HashMap<String, Arc<Upstream>>>,
+}
+
+impl Upstreams {
+    pub fn new(config: &UpstreamConfig) -> Result<Self> {
+        // Upstream calls are retried, don't let one hung connection hold a request up indefinitely
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.connect_timeout())
+            .timeout(config.request_timeout())
+            .build()?;
+        Ok(Upstreams {
+            client,
+            limits: config.limits.clone(),
+            attempt_timeout: config.request_timeout(),
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    fn upstream(&self, host: &str) -> Arc<Upstream> {
+        self.hosts
+            .lock()
+            .expect("Upstreams poisoned")
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let limits = self
+                    .limits
+                    .get(host)
+                    .copied()
+                    .unwrap_or_else(|| Limits::for_host(host));
+                Arc::new(Upstream::new(host, limits))
+            })
+            .clone()
+    }
+
+    /// Make a call to a host within its limits, retrying failures with backoff while its circuit is closed.
+    /// Described by what, for logging.
+    pub async fn run<T, F, Fut>(&self, host: &str, what: &str, call: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Attempt<T>>,
+    {
+        self.run_attempts(host, what, ATTEMPTS, call).await
+    }
+
+    async fn run_attempts<T, F, Fut>(
+        &self,
+        host: &str,
+        what: &str,
+        attempts: u32,
+        mut call: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Attempt<T>>,
+    {
+        let upstream = self.upstream(host);
+        let attempt_timeout = self.attempt_timeout;
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            if !upstream.admit() {
+                upstream.refused.fetch_add(1, Ordering::Relaxed);
+                return Err(eyre!("{what} refused, {host} is failing"));
+            }
+            let outcome = {
+                let _permit = upstream.permits.acquire().await?;
+                upstream.take_token().await;
+                upstream.attempts.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                match timeout(attempt_timeout, call()).await {
+                    Ok(outcome) => (outcome, Some(started.elapsed())),
+                    Err(_) => (
+                        Attempt::Failed(eyre!("{what} timed out after {attempt_timeout:?}"), None),
+                        None,
+                    ),
+                }
+            };
+            let (error, retry_after) = match outcome {
+                (Attempt::Success(result), latency) => {
+                    upstream.record_last(latency, None);
+                    upstream.record(true);
+                    return Ok(result);
+                }
+                (Attempt::Rejected(error), latency) => {
+                    upstream.record_last(latency, None);
+                    upstream.record(true);
+                    return Err(error);
+                }
+                (Attempt::Failed(error, retry_after), latency) => {
+                    upstream.record_last(latency, Some(&error));
+                    upstream.record(false);
+                    (error, retry_after)
+                }
+            };
+            if attempt >= attempts {
+                return Err(error.wrap_err(format!("{what} failed after {attempt} attempts")));
+            }
+            let wait = jittered(retry_after.unwrap_or(backoff).min(MAX_BACKOFF));
+            warn!("{what} failed on attempt {attempt}, retrying in {wait:?}: {error}");
+            sleep(wait).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Send a request within its host's limits, retrying on 429s, server errors and dropped connections.
+    /// Other error statuses are returned as errors without retrying.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        self.send_attempts(request, ATTEMPTS).await
+    }
+
+    /// Send a request once, outside its host's limits and circuit, for checking the host is up.
+    /// Health checks neither use up the host's rate limit nor trip its breaker, and still reach it while open.
+    pub async fn probe(&self, request: RequestBuilder) -> Result<Response> {
+        let response = request.timeout(self.attempt_timeout).send().await?;
+        Ok(response.error_for_status()?)
+    }
+
+    async fn send_attempts(&self, request: RequestBuilder, attempts: u32) -> Result<Response> {
+        let (client, request) = request.build_split();
+        let request = request?;
+        let host = request
+            .url()
+            .host_str()
+            .ok_or(eyre!("No host in {}", request.url()))?
+            .to_string();
+        let what = format!("{} {}{}", request.method(), host, request.url().path());
+        let client = &client;
+        self.run_attempts(&host, &what, attempts, || {
+            let request = request.try_clone();
+            async move {
+                let Some(request) = request else {
+                    return Attempt::Rejected(eyre!("Request body can't be resent"));
+                };
+                Attempt::from_response(client.execute(request).await)
+            }
+        })
+        .await
+    }
+
+    /// How calls to a host are being limited, or None if it hasn't been called yet
+    pub fn status(&self, host: &str) -> Option<UpstreamStatus> {
+        let hosts = self.hosts.lock().expect("Upstreams poisoned");
+        hosts.get(host).map(|upstream| upstream.status())
+    }
+
+    /// How every host called so far is being limited, for the admin endpoint
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        let hosts = self.hosts.lock().expect("Upstreams poisoned");
+        let mut statuses: Vec<UpstreamStatus> =
+            hosts.values().map(|upstream| upstream.status()).collect();
+        statuses.sort_by(|a, b| a.host.cmp(&b.host));
+        statuses
+    }
+}